@@ -98,7 +98,9 @@ fn generate_manpages(out_dir: PathBuf) -> std::io::Result<()> {
 
     generate_command_manpage(crunchy_cli_core::Cli::command(), &out_dir, "")?;
     generate_command_manpage(crunchy_cli_core::Archive::command(), &out_dir, "archive")?;
+    generate_command_manpage(crunchy_cli_core::Cache::command(), &out_dir, "cache")?;
     generate_command_manpage(crunchy_cli_core::Download::command(), &out_dir, "download")?;
+    generate_command_manpage(crunchy_cli_core::Info::command(), &out_dir, "info")?;
     generate_command_manpage(crunchy_cli_core::Login::command(), &out_dir, "login")?;
 
     Ok(())
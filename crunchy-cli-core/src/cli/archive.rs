@@ -1,24 +1,37 @@
 use crate::cli::log::tab_info;
 use crate::cli::utils::{
-    all_locale_in_locales, download_segments, find_multiple_seasons_with_same_number,
-    find_resolution, interactive_season_choosing, FFmpegPreset,
+    all_locale_in_locales, check_disk_space, check_ffmpeg, download_segments, ffmpeg_end_event,
+    ffmpeg_start_event, find_multiple_seasons_with_same_number, find_resolution,
+    interactive_season_choosing, preset_args, run_ffmpeg, run_mkvmerge, run_pipeline, AudioPreset,
+    FFmpegPreset,
 };
+use crate::utils::batch::{inherit_unset_args, read_batch_file};
+use crate::utils::cache;
 use crate::utils::context::Context;
+use crate::utils::ffmpeg::{ffmpeg_command, ffmpeg_command_line};
 use crate::utils::format::Format;
 use crate::utils::log::progress;
-use crate::utils::os::{free_file, has_ffmpeg, is_special_file, tempfile};
+use crate::utils::mkvmerge::{has_mkvmerge, mkvmerge_command_line};
+use crate::utils::os::{free_file, is_special_file, tempfile};
 use crate::utils::parse::{parse_url, UrlFilter};
+use crate::utils::probe::{probe, StreamKind};
+use crate::utils::report::{Report, ReportItem};
 use crate::utils::sort::{sort_formats_after_seasons, sort_seasons_after_number};
-use crate::utils::subtitle::{download_subtitle, Subtitle};
-use crate::utils::video::get_video_length;
+use crate::utils::subtitle::{fetch_subtitle, write_subtitle, Subtitle};
 use crate::Execute;
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use clap::{CommandFactory, FromArgMatches};
 use crunchyroll_rs::media::Resolution;
-use crunchyroll_rs::{Locale, Media, MediaCollection, Series};
+use crunchyroll_rs::{Episode, Locale, Media, MediaCollection, Series};
+use futures_util::future::{try_join3, try_join_all};
+use futures_util::{stream, StreamExt};
+use indicatif::HumanBytes;
 use log::{debug, error, info};
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use tempfile::TempPath;
+use tokio::io::AsyncReadExt;
 
 #[derive(Clone, Debug)]
 pub enum MergeBehavior {
@@ -38,6 +51,22 @@ impl MergeBehavior {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub enum Muxer {
+    Ffmpeg,
+    Mkvmerge,
+}
+
+impl Muxer {
+    fn parse(s: &str) -> Result<Muxer, String> {
+        Ok(match s.to_lowercase().as_str() {
+            "ffmpeg" => Muxer::Ffmpeg,
+            "mkvmerge" => Muxer::Mkvmerge,
+            _ => return Err(format!("'{}' is not a valid muxer", s)),
+        })
+    }
+}
+
 #[derive(Debug, clap::Parser)]
 #[clap(about = "Archive a video")]
 #[command(arg_required_else_help(true))]
@@ -104,6 +133,27 @@ pub struct Archive {
     #[arg(long)]
     #[arg(value_parser = FFmpegPreset::parse)]
     ffmpeg_preset: Option<FFmpegPreset>,
+    #[arg(help = format!("Preset for audio converting. \
+    Available presets: \n  {}", AudioPreset::available_matches_human_readable().join("\n  ")))]
+    #[arg(long_help = format!("Preset for audio converting. \
+    Every audio track is transcoded to the given codec, optionally with the given bitrate. \
+    Can be combined with '--ffmpeg-preset' and overrides its audio settings. \
+    Available presets: \n  {}", AudioPreset::available_matches_human_readable().join("\n  ")))]
+    #[arg(long)]
+    #[arg(value_parser = AudioPreset::parse)]
+    audio_preset: Option<AudioPreset>,
+
+    #[arg(
+        help = "Program which merges all tracks into the mkv file. Valid muxers are 'ffmpeg' and 'mkvmerge'"
+    )]
+    #[arg(
+        long_help = "Program which merges all tracks into the mkv file. Valid muxers are 'ffmpeg' and 'mkvmerge'. \
+    'mkvmerge' (part of MKVToolNix) handles languages, track names and flags better than ffmpeg but cannot convert videos, \
+    so it cannot be used together with '--ffmpeg-preset' or '--audio-preset'. FFmpeg is still needed to download the videos"
+    )]
+    #[arg(long, default_value = "ffmpeg")]
+    #[arg(value_parser = Muxer::parse)]
+    muxer: Muxer,
 
     #[arg(
         help = "Set which subtitle language should be set as default / auto shown when starting a video"
@@ -115,20 +165,113 @@ pub struct Archive {
     #[arg(long, default_value_t = false)]
     skip_existing: bool,
 
+    #[arg(help = "Only show what would be archived, without downloading anything")]
+    #[arg(
+        long_help = "Only show what would be archived, without downloading anything. \
+    Url filters, season choosing, resolution matching and file renaming are applied as usual. \
+    Prints the output path, selected tracks, ffmpeg command and estimated size of every episode"
+    )]
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    #[arg(help = "Continue with the next episode if one fails and print a summary at the end")]
+    #[arg(
+        long_help = "Continue with the next episode if one fails and print a summary at the end. \
+    The summary lists all succeeded, skipped and failed episodes. If any episode failed, the exit code is non-zero"
+    )]
+    #[arg(long, default_value_t = false)]
+    continue_on_error: bool,
+
+    #[arg(help = "Write a json report of all processed episodes to the given file")]
+    #[arg(
+        long_help = "Write a json report of all processed episodes to the given file. \
+    It contains the url, episode id, output path, selected stream (resolution, fps, bandwidth), audio and subtitle languages, \
+    written bytes, duration, status and error message of every episode"
+    )]
+    #[arg(long)]
+    report: Option<PathBuf>,
+
     #[arg(help = "Ignore interactive input")]
     #[arg(short, long, default_value_t = false)]
     yes: bool,
 
+    #[arg(help = "File with url(s) to archive, one per line. Use '-' to read from stdin")]
+    #[arg(
+        long_help = "File with url(s) to archive, one per line. Use '-' to read from stdin (requires '--yes'). \
+    Every line can contain additional arguments which overwrite the ones given on the command line for this url only, \
+    e.g. `https://www.crunchyroll.com/series/12345678/example[S2] --locale de-DE -o \"{title}.mkv\"`. \
+    Empty lines and lines starting with '#' are ignored"
+    )]
+    #[arg(long)]
+    batch_file: Option<String>,
+
     #[arg(help = "Crunchyroll series url(s)")]
     urls: Vec<String>,
+
+    #[arg(skip)]
+    batch: Vec<Archive>,
+}
+
+impl Archive {
+    /// Create a new [`Archive`] from the arguments of a batch file line. All arguments which are
+    /// not given in the line are taken from `self`.
+    fn merge_batch_line(&self, args: Vec<String>) -> Result<Archive> {
+        let matches = Archive::command()
+            .no_binary_name(true)
+            .try_get_matches_from(args)?;
+        let mut archive = Archive::from_arg_matches(&matches)?;
+
+        if archive.batch_file.is_some() {
+            bail!("'--batch-file' cannot be used inside a batch file")
+        } else if archive.continue_on_error {
+            // these apply to the whole run and not only to the urls of a single line
+            bail!("'--continue-on-error' cannot be used inside a batch file")
+        } else if archive.report.is_some() {
+            bail!("'--report' cannot be used inside a batch file")
+        } else if archive.urls.is_empty() {
+            bail!("No url given")
+        }
+
+        inherit_unset_args!(
+            self,
+            archive,
+            matches,
+            [
+                locale,
+                subtitle,
+                output,
+                resolution,
+                merge,
+                ffmpeg_preset,
+                audio_preset,
+                muxer,
+                default_subtitle,
+                skip_existing,
+                dry_run,
+                yes
+            ]
+        );
+
+        Ok(archive)
+    }
 }
 
 #[async_trait::async_trait(?Send)]
 impl Execute for Archive {
     fn pre_check(&mut self) -> Result<()> {
-        if !has_ffmpeg() {
-            bail!("FFmpeg is needed to run this command")
-        } else if PathBuf::from(&self.output)
+        if self.muxer == Muxer::Mkvmerge {
+            if self.ffmpeg_preset.is_some() || self.audio_preset.is_some() {
+                bail!("mkvmerge cannot convert videos, '--ffmpeg-preset' and '--audio-preset' only work with the ffmpeg muxer")
+            } else if is_special_file(PathBuf::from(&self.output)) {
+                bail!("mkvmerge cannot write to special files, only the ffmpeg muxer can")
+            } else if !self.dry_run && !has_mkvmerge() {
+                bail!("mkvmerge is needed to use it as muxer")
+            }
+        }
+        if !self.dry_run {
+            check_ffmpeg(self.ffmpeg_preset.as_ref(), self.audio_preset.as_ref())?
+        }
+        if PathBuf::from(&self.output)
             .extension()
             .unwrap_or_default()
             .to_string_lossy()
@@ -141,33 +284,132 @@ impl Execute for Archive {
         self.locale = all_locale_in_locales(self.locale.clone());
         self.subtitle = all_locale_in_locales(self.subtitle.clone());
 
+        if let Some(batch_file) = &self.batch_file {
+            for (line, args) in read_batch_file(batch_file, self.yes)? {
+                let mut archive = self
+                    .merge_batch_line(args)
+                    .map_err(|e| anyhow!("line {} of batch file: {}", line, e))?;
+                archive
+                    .pre_check()
+                    .map_err(|e| anyhow!("line {} of batch file: {}", line, e))?;
+                self.batch.push(archive)
+            }
+        }
+
         Ok(())
     }
 
     async fn execute(self, ctx: Context) -> Result<()> {
+        let report = Report::new(self.continue_on_error);
+
+        let result: Result<()> = async {
+            let mut jobs = vec![(&self, self.resolve_formats(&ctx, &report).await?)];
+            for archive in &self.batch {
+                jobs.push((archive, archive.resolve_formats(&ctx, &report).await?))
+            }
+
+            let mut temp_sizes = vec![];
+            let mut outputs = vec![];
+            for (archive, archive_formats) in &jobs {
+                // dry run lines of a batch file don't write anything. if the whole run is a dry
+                // run, the estimate is still shown
+                if archive.dry_run && !self.dry_run {
+                    continue;
+                }
+                let (job_temp_sizes, job_outputs) = archive.estimate_space(archive_formats);
+                temp_sizes.extend(job_temp_sizes);
+                outputs.extend(job_outputs)
+            }
+            // the temporary files of all episodes which are downloading, waiting for ffmpeg or
+            // being merged exist together
+            temp_sizes.sort_unstable_by(|a, b| b.cmp(a));
+            let temp_size = temp_sizes
+                .into_iter()
+                .take(ctx.jobs * 2 + ctx.encode_jobs)
+                .sum();
+            check_disk_space(temp_size, outputs, self.yes, self.dry_run)?;
+
+            // output paths which are taken by an episode of this run. as the files are not created
+            // before the episode is archived (or never in a dry run) they are needed to detect
+            // collisions between the episodes of the run
+            let mut reserved_paths = HashSet::new();
+            let mut queue = vec![];
+            for (archive, archive_formats) in jobs {
+                for (url, formats, subtitles, path) in
+                    archive.prepare_formats(&report, archive_formats, &mut reserved_paths)
+                {
+                    queue.push((archive, url, formats, subtitles, path))
+                }
+            }
+            archive_queue(&ctx, &report, queue).await
+        }
+        .await;
+
+        if let Some(report_path) = &self.report {
+            if let Err(e) = report.write_json(report_path) {
+                if result.is_ok() {
+                    return Err(e);
+                }
+                // the error which made the run fail is more important, only log this one
+                error!("Failed to write report to {}: {}", report_path.display(), e)
+            }
+        }
+        result?;
+
+        if self.continue_on_error {
+            report.print_summary()
+        }
+        let failed_count = report.failed_count();
+        if failed_count > 0 {
+            bail!("{} item(s) failed", failed_count)
+        }
+
+        Ok(())
+    }
+}
+
+impl Archive {
+    /// Resolve all urls to the formats and subtitles of every episode which should be archived,
+    /// together with the url they belong to.
+    async fn resolve_formats(
+        &self,
+        ctx: &Context,
+        report: &Report,
+    ) -> Result<Vec<(String, Vec<Format>, Vec<Subtitle>)>> {
         let mut parsed_urls = vec![];
+        let mut queue = vec![];
 
         for (i, url) in self.urls.iter().enumerate() {
-            let progress_handler = progress!("Parsing url {}", i + 1);
-            match parse_url(&ctx.crunchy, url.clone(), true).await {
+            report.set_current_url(url);
+            let progress_handler = progress!(stage = "url_parse"; "Parsing url {}", i + 1);
+            match parse_url(ctx, url.clone(), true).await {
                 Ok((media_collection, url_filter)) => {
-                    parsed_urls.push((media_collection, url_filter));
-                    progress_handler.stop(format!("Parsed url {}", i + 1))
+                    parsed_urls.push((url, media_collection, url_filter));
+                    progress_handler.stop(format!("Parsed url {}", i + 1));
+                    info!(target: "event", event = "url_parsed", url = url.as_str(); "Parsed url {}", i + 1)
                 }
-                Err(e) => bail!("url {} could not be parsed: {}", url, e),
+                Err(e) => report.failed(url, anyhow!("url {} could not be parsed: {}", url, e))?,
             }
         }
 
-        for (i, (media_collection, url_filter)) in parsed_urls.into_iter().enumerate() {
-            let progress_handler = progress!("Fetching series details");
-            let archive_formats = match media_collection {
+        for (i, (url, media_collection, url_filter)) in parsed_urls.into_iter().enumerate() {
+            report.set_current_url(url);
+            let progress_handler = progress!(stage = "fetch_details"; "Fetching series details");
+            let archive_formats_result = match media_collection {
                 MediaCollection::Series(series) => {
-                    formats_from_series(&self, series, &url_filter).await?
+                    formats_from_series(ctx, self, series, &url_filter, report).await
+                }
+                MediaCollection::Season(_) => Err(anyhow!("Archiving a season is not supported")),
+                MediaCollection::Episode(episode) => Err(anyhow!("Archiving a episode is not supported. Use url filtering instead to specify the episode (https://www.crunchyroll.com/series/{}/{}[S{}E{}])", episode.metadata.series_id, episode.metadata.series_slug_title, episode.metadata.season_number, episode.metadata.episode_number)),
+                MediaCollection::MovieListing(_) => Err(anyhow!("Archiving a movie listing is not supported")),
+                MediaCollection::Movie(_) => Err(anyhow!("Archiving a movie is not supported"))
+            };
+            let archive_formats = match archive_formats_result {
+                Ok(archive_formats) => archive_formats,
+                Err(e) => {
+                    report.failed(url, e)?;
+                    continue;
                 }
-                MediaCollection::Season(_) => bail!("Archiving a season is not supported"),
-                MediaCollection::Episode(episode) => bail!("Archiving a episode is not supported. Use url filtering instead to specify the episode (https://www.crunchyroll.com/series/{}/{}[S{}E{}])", episode.metadata.series_id, episode.metadata.series_slug_title, episode.metadata.season_number, episode.metadata.episode_number),
-                MediaCollection::MovieListing(_) => bail!("Archiving a movie listing is not supported"),
-                MediaCollection::Movie(_) => bail!("Archiving a movie is not supported")
             };
 
             if archive_formats.is_empty() {
@@ -229,115 +471,383 @@ impl Execute for Archive {
                 }
             }
 
-            for (formats, mut subtitles) in archive_formats {
-                let (primary, additionally) = formats.split_first().unwrap();
+            queue.extend(
+                archive_formats
+                    .into_iter()
+                    .map(|(formats, subtitles)| (url.clone(), formats, subtitles)),
+            )
+        }
 
-                let formatted_path = primary.format_path((&self.output).into(), true);
-                let (path, changed) = free_file(formatted_path.clone());
+        Ok(queue)
+    }
 
-                if changed && self.skip_existing {
-                    debug!(
-                        "Skipping already existing file '{}'",
-                        formatted_path.to_string_lossy()
-                    );
-                    continue;
-                }
+    /// Estimated space which is needed to archive the given episodes. Returns the space every
+    /// episode needs in the temp directory (temporary files are removed after every episode) and
+    /// the estimated size of every output file.
+    fn estimate_space(
+        &self,
+        archive_formats: &[(String, Vec<Format>, Vec<Subtitle>)],
+    ) -> (Vec<u64>, Vec<(PathBuf, u64)>) {
+        let mut temp_sizes = vec![];
+        let mut outputs = vec![];
 
-                info!(
-                    "Downloading {} to '{}'",
-                    primary.title,
-                    if is_special_file(&path) {
-                        path.to_str().unwrap()
-                    } else {
-                        path.file_name().unwrap().to_str().unwrap()
-                    }
-                );
-                tab_info!(
-                    "Episode: S{:02}E{:02}",
-                    primary.season_number,
-                    primary.episode_number
+        for (_, formats, _) in archive_formats {
+            let path = formats[0].format_path((&self.output).into(), true);
+            if self.skip_existing && path.exists() {
+                continue;
+            }
+
+            // every format is downloaded to a temporary .ts / .aac file before they're merged
+            let estimated_size = formats.iter().map(|f| f.estimated_size()).sum();
+            temp_sizes.push(estimated_size);
+            if !is_special_file(&path) {
+                outputs.push((path, estimated_size))
+            }
+        }
+
+        (temp_sizes, outputs)
+    }
+
+    /// Check which of the episodes should be archived and to which path. Episodes which are
+    /// skipped (or only printed in a dry run) are reported directly, all others are returned
+    /// together with the url they belong to and their output path.
+    #[allow(clippy::type_complexity)]
+    fn prepare_formats(
+        &self,
+        report: &Report,
+        archive_formats: Vec<(String, Vec<Format>, Vec<Subtitle>)>,
+        reserved_paths: &mut HashSet<PathBuf>,
+    ) -> Vec<(String, Vec<Format>, Vec<Subtitle>, PathBuf)> {
+        let mut prepared = vec![];
+
+        for (url, formats, subtitles) in archive_formats {
+            report.set_current_url(&url);
+            let (primary, additionally) = formats.split_first().unwrap();
+
+            let formatted_path = primary.format_path((&self.output).into(), true);
+            let (path, changed) = free_file(formatted_path.clone(), reserved_paths);
+
+            if changed && self.skip_existing {
+                debug!(
+                    "Skipping already existing file '{}'",
+                    formatted_path.to_string_lossy()
                 );
+                report.skipped(primary, "file already exists");
+                continue;
+            }
+
+            if self.dry_run {
+                self.print_format(&formats, &subtitles, &path);
+
+                let (videos, audios, subtitles) =
+                    self.split_tracks(primary, additionally, subtitles);
+                let video_paths: Vec<(PathBuf, &Format)> = videos
+                    .into_iter()
+                    .map(|f| (PathBuf::from(format!("<video {}>.ts", f.audio)), f))
+                    .collect();
+                let audio_paths: Vec<(PathBuf, &Format)> = audios
+                    .into_iter()
+                    .map(|f| (PathBuf::from(format!("<audio {}>.aac", f.audio)), f))
+                    .collect();
+                let subtitle_paths: Vec<(PathBuf, Subtitle)> = subtitles
+                    .into_iter()
+                    .map(|s| {
+                        (
+                            PathBuf::from(format!(
+                                "<subtitle {} {}>.ass",
+                                s.stream_subtitle.locale, s.audio_locale
+                            )),
+                            s,
+                        )
+                    })
+                    .collect();
+
                 tab_info!(
-                    "Audio: {} (primary), {}",
-                    primary.audio,
-                    additionally
-                        .iter()
-                        .map(|a| a.audio.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", ")
+                    "Tracks: {} video, {} audio only, {} subtitle",
+                    video_paths.len(),
+                    audio_paths.len(),
+                    subtitle_paths.len()
                 );
                 tab_info!(
-                    "Subtitle: {}",
-                    subtitles
-                        .iter()
-                        .filter(|s| s.primary) // Don't print subtitles of non-primary streams. They might get removed depending on the merge behavior.
-                        .map(|s| {
-                            if let Some(default) = &self.default_subtitle {
-                                if default == &s.stream_subtitle.locale {
-                                    return format!("{} (primary)", default);
-                                }
-                            }
-                            s.stream_subtitle.locale.to_string()
-                        })
-                        .collect::<Vec<String>>()
-                        .join(", ")
+                    "Estimated size: {}",
+                    HumanBytes(formats.iter().map(|f| f.estimated_size()).sum())
                 );
-                tab_info!("Resolution: {}", primary.stream.resolution);
-                tab_info!("FPS: {:.2}", primary.stream.fps);
-
-                let mut video_paths = vec![];
-                let mut audio_paths = vec![];
-                let mut subtitle_paths = vec![];
-
-                video_paths.push((download_video(&ctx, primary, false).await?, primary));
-                for additional in additionally {
-                    let identical_video = additionally
-                        .iter()
-                        .all(|a| a.stream.bandwidth == primary.stream.bandwidth);
-                    let only_audio = match self.merge {
-                        MergeBehavior::Auto => identical_video,
-                        MergeBehavior::Audio => true,
-                        MergeBehavior::Video => false,
-                    };
-                    let path = download_video(&ctx, additional, only_audio).await?;
-                    if only_audio {
-                        audio_paths.push((path, additional))
-                    } else {
-                        video_paths.push((path, additional))
+                match self.muxer {
+                    Muxer::Ffmpeg => {
+                        let args = mkv_ffmpeg_args(
+                            self,
+                            &path,
+                            &video_paths,
+                            &audio_paths,
+                            &subtitle_paths,
+                        );
+                        tab_info!("FFmpeg: {}", ffmpeg_command_line(&args))
                     }
-
-                    // Remove subtitles of forcibly deleted video
-                    if matches!(self.merge, MergeBehavior::Audio) && !identical_video {
-                        subtitles.retain(|s| s.episode_id != additional.episode_id);
+                    Muxer::Mkvmerge => {
+                        let args = mkv_mkvmerge_args(
+                            self,
+                            &path,
+                            &video_paths,
+                            &audio_paths,
+                            &subtitle_paths,
+                        );
+                        tab_info!("mkvmerge: {}", mkvmerge_command_line(&args))
                     }
                 }
+                report.skipped(ReportItem::from(primary).output(&path), "dry run");
+                reserved_paths.insert(path);
+                continue;
+            }
+
+            reserved_paths.insert(path.clone());
+            prepared.push((url, formats, subtitles, path))
+        }
+
+        prepared
+    }
+
+    /// Print the selected format. It's printed as single message, as multiple episodes may be
+    /// printed at the same time (`--jobs`) and their lines must not mix.
+    fn print_format(&self, formats: &[Format], subtitles: &[Subtitle], path: &Path) {
+        let (primary, additionally) = formats.split_first().unwrap();
+
+        let details = [
+            format!(
+                "Episode: S{:02}E{:02}",
+                primary.season_number, primary.episode_number
+            ),
+            format!(
+                "Audio: {} (primary), {}",
+                primary.audio,
+                additionally
+                    .iter()
+                    .map(|a| a.audio.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            format!(
+                "Subtitle: {}",
+                subtitles
+                    .iter()
+                    .filter(|s| s.primary) // Don't print subtitles of non-primary streams. They might get removed depending on the merge behavior.
+                    .map(|s| {
+                        if let Some(default) = &self.default_subtitle {
+                            if default == &s.stream_subtitle.locale {
+                                return format!("{} (primary)", default);
+                            }
+                        }
+                        s.stream_subtitle.locale.to_string()
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            format!("Resolution: {}", primary.stream.resolution),
+            format!("FPS: {:.2}", primary.stream.fps),
+        ];
 
-                let (primary_video, _) = video_paths.get(0).unwrap();
-                let primary_video_length = get_video_length(primary_video.to_path_buf()).unwrap();
-                for subtitle in subtitles {
-                    subtitle_paths.push((
-                        download_subtitle(subtitle.stream_subtitle.clone(), primary_video_length)
-                            .await?,
-                        subtitle,
-                    ))
+        info!(
+            event = "format_selected",
+            episode_id = primary.episode_id.as_str(),
+            output:% = path.display(),
+            resolution:% = primary.stream.resolution,
+            fps = primary.stream.fps,
+            bandwidth = primary.stream.bandwidth,
+            audio:% = formats.iter().map(|f| f.audio.to_string()).collect::<Vec<String>>().join(","),
+            // every track has its own episode id, which is used by its download events
+            episode_ids:% = formats.iter().map(|f| f.episode_id.as_str()).collect::<Vec<&str>>().join(",");
+            "{} {} to '{}'\n\t{}",
+            if self.dry_run { "Would download" } else { "Downloading" },
+            primary.title,
+            if is_special_file(path) {
+                path.to_str().unwrap()
+            } else {
+                path.file_name().unwrap().to_str().unwrap()
+            },
+            details.join("\n\t")
+        );
+    }
+
+    /// Download all tracks of an episode. The mkv is generated from them in a separate stage, see
+    /// [`archive_queue`].
+    async fn download_episode(
+        &self,
+        ctx: &Context,
+        mut formats: Vec<Format>,
+        mut subtitles: Vec<Subtitle>,
+    ) -> Result<DownloadedEpisode> {
+        // all formats are resolved before the first download starts. the stream and subtitle urls
+        // of this episode may have expired in the meantime, so they are fetched again right before
+        // they are needed, unless they were resolved only shortly before
+        for format in formats.iter_mut().filter(|f| !f.is_stream_fresh()) {
+            let streams = format.refresh_stream(ctx).await?;
+            for subtitle in subtitles
+                .iter_mut()
+                .filter(|s| s.episode_id == format.episode_id)
+            {
+                if let Some(refreshed) = streams.subtitles.get(&subtitle.stream_subtitle.locale) {
+                    subtitle.stream_subtitle = refreshed.clone()
                 }
+            }
+        }
+
+        let (primary, additionally) = formats.split_first().unwrap();
+        let (videos, audios, subtitles) = self.split_tracks(primary, additionally, subtitles);
 
-                let progess_handler = progress!("Generating mkv");
-                generate_mkv(&self, path, video_paths, audio_paths, subtitle_paths)?;
-                progess_handler.stop("Mkv generated")
+        // all tracks are downloaded at the same time. together they are still bound by the
+        // number of segments which can be downloaded concurrently
+        let (videos, audios, raw_subtitles) = try_join3(
+            try_join_all(videos.into_iter().map(|video| async move {
+                Ok((download_video(ctx, video, false).await?, video.clone()))
+            })),
+            try_join_all(audios.into_iter().map(|audio| async move {
+                Ok((download_video(ctx, audio, true).await?, audio.clone()))
+            })),
+            try_join_all(
+                subtitles
+                    .iter()
+                    .map(|subtitle| fetch_subtitle(ctx, &subtitle.stream_subtitle)),
+            ),
+        )
+        .await?;
+
+        // the merge maps one video and one audio stream of every video file and one audio stream of
+        // every audio file. a download which lacks them would end up as mislabeled tracks
+        let mut probes = vec![];
+        for (path, format) in &videos {
+            let probe = probe(path)?;
+            if !probe.has_stream(StreamKind::Video) || !probe.has_stream(StreamKind::Audio) {
+                bail!(
+                    "Downloaded {} video has no video or audio stream",
+                    format.audio
+                )
+            }
+            probes.push(probe)
+        }
+        for (path, format) in &audios {
+            if !probe(path)?.has_stream(StreamKind::Audio) {
+                bail!("Downloaded {} audio has no audio stream", format.audio)
             }
         }
 
-        Ok(())
+        // the subtitles can only be fixed after the video length is known
+        let primary_video_length = probes.first().unwrap().length();
+        let mut downloaded = DownloadedEpisode {
+            videos,
+            audios,
+            subtitles: vec![],
+        };
+        for (raw, subtitle) in raw_subtitles.into_iter().zip(subtitles) {
+            downloaded
+                .subtitles
+                .push((write_subtitle(raw, primary_video_length)?, subtitle))
+        }
+
+        Ok(downloaded)
     }
+
+    /// Split the formats of an episode into the ones which are stored with video (the primary
+    /// format is always the first one) and the ones which are stored as audio only, depending on
+    /// the merge behavior. Subtitles which belong to a dropped video are removed.
+    fn split_tracks<'a>(
+        &self,
+        primary: &'a Format,
+        additionally: &'a [Format],
+        mut subtitles: Vec<Subtitle>,
+    ) -> (Vec<&'a Format>, Vec<&'a Format>, Vec<Subtitle>) {
+        let mut videos = vec![primary];
+        let mut audios = vec![];
+
+        for additional in additionally {
+            let identical_video = additionally
+                .iter()
+                .all(|a| a.stream.bandwidth == primary.stream.bandwidth);
+            let only_audio = match self.merge {
+                MergeBehavior::Auto => identical_video,
+                MergeBehavior::Audio => true,
+                MergeBehavior::Video => false,
+            };
+            if only_audio {
+                audios.push(additional)
+            } else {
+                videos.push(additional)
+            }
+
+            // Remove subtitles of forcibly deleted video
+            if matches!(self.merge, MergeBehavior::Audio) && !identical_video {
+                subtitles.retain(|s| s.episode_id != additional.episode_id);
+            }
+        }
+
+        (videos, audios, subtitles)
+    }
+}
+
+/// Archive all queued episodes. Downloading the tracks and generating the mkv run as separate
+/// stages (see [`run_pipeline`]), so the next episode is already downloaded while ffmpeg merges the
+/// previous one. An episode is announced when its download starts and its result is reported in
+/// the order of the queue, so the output does not depend on which episode finishes first.
+#[allow(clippy::type_complexity)]
+async fn archive_queue(
+    ctx: &Context,
+    report: &Report,
+    queue: Vec<(&Archive, String, Vec<Format>, Vec<Subtitle>, PathBuf)>,
+) -> Result<()> {
+    let queue = &queue;
+    run_pipeline(
+        ctx,
+        queue.len(),
+        |i| async move {
+            let (archive, _, formats, subtitles, path) = &queue[i];
+            archive.print_format(formats, subtitles, path);
+            archive
+                .download_episode(ctx, formats.clone(), subtitles.clone())
+                .await
+        },
+        |i, downloaded| async move {
+            let (archive, _, _, _, path) = &queue[i];
+            generate_mkv(archive, path.clone(), downloaded).await
+        },
+        |i, duration, result| {
+            let (_, url, formats, subtitles, path) = &queue[i];
+            report.set_current_url(url);
+            let item = ReportItem::from(&formats[0])
+                .audio(formats.iter().map(|f| f.audio.clone()).collect())
+                .subtitles(
+                    subtitles
+                        .iter()
+                        .map(|s| s.stream_subtitle.locale.clone())
+                        .collect(),
+                )
+                .output(path)
+                .duration(duration);
+            match result {
+                Ok(_) => {
+                    info!(target: "event", event = "file_finished", path:% = path.display(); "Downloaded {}", formats[0].title);
+                    report.succeeded(item);
+                    Ok(())
+                }
+                Err(e) => report.failed(item, e),
+            }
+        },
+    )
+    .await
+}
+
+/// Tracks of an episode which are downloaded and waiting to be merged into the mkv.
+struct DownloadedEpisode {
+    videos: Vec<(TempPath, Format)>,
+    audios: Vec<(TempPath, Format)>,
+    subtitles: Vec<(TempPath, Subtitle)>,
 }
 
 async fn formats_from_series(
+    ctx: &Context,
     archive: &Archive,
     series: Media<Series>,
     url_filter: &UrlFilter,
+    report: &Report,
 ) -> Result<Vec<(Vec<Format>, Vec<Subtitle>)>> {
-    let mut seasons = series.seasons().await?;
+    let mut seasons = cache::seasons(ctx, &series).await?;
 
     // filter any season out which does not contain the specified audio languages
     for season in sort_seasons_after_number(seasons.clone()) {
@@ -385,47 +895,32 @@ async fn formats_from_series(
     let mut result: Vec<(Vec<Format>, Vec<Subtitle>)> = Vec::new();
     let mut primary_season = true;
     for season in seasons {
-        let episodes = season.episodes().await?;
-
-        for episode in episodes.iter() {
-            if !url_filter.is_episode_valid(
-                episode.metadata.episode_number,
-                episode.metadata.season_number,
-            ) {
+        let episodes = match cache::episodes(ctx, &season).await {
+            Ok(episodes) => episodes,
+            Err(e) => {
+                report.failed(format!("{} {}", series.title, season.title), e)?;
+                primary_season = false;
                 continue;
             }
+        };
 
-            let streams = episode.streams().await?;
-            let streaming_data = streams.hls_streaming_data(None).await?;
-            let Some(stream) = find_resolution(streaming_data, &archive.resolution) else {
-                bail!(
-                    "Resolution ({}x{}) is not available for episode {} ({}) of season {} ({}) of {}",
-                    archive.resolution.width,
-                    archive.resolution.height,
-                    episode.metadata.episode_number,
-                    episode.title,
-                    episode.metadata.season_number,
-                    episode.metadata.season_title,
-                    episode.metadata.series_title
-                )
-            };
-
-            let mut formats: Vec<Format> = Vec::new();
-            let mut subtitles: Vec<Subtitle> = Vec::new();
-            subtitles.extend(archive.subtitle.iter().filter_map(|l| {
-                let stream_subtitle = streams.subtitles.get(l).cloned()?;
-                let subtitle = Subtitle {
-                    stream_subtitle,
-                    audio_locale: episode.metadata.audio_locale.clone(),
-                    episode_id: episode.id.clone(),
-                    forced: !episode.metadata.is_subbed,
-                    primary: primary_season,
-                };
-                Some(subtitle)
-            }));
-            formats.push(Format::new_from_episode(episode, &episodes, stream, vec![]));
-
-            result.push((formats, subtitles));
+        // the episodes are resolved concurrently but their results are returned in the order of
+        // the season
+        let season_episodes = &episodes;
+        let mut formats = stream::iter(episodes.iter().filter(|e| {
+            url_filter.is_episode_valid(e.metadata.episode_number, e.metadata.season_number)
+        }))
+        .map(move |episode| async move {
+            let format =
+                format_from_episode(ctx, archive, episode, season_episodes, primary_season).await;
+            (episode, format)
+        })
+        .buffered(ctx.concurrent_requests);
+        while let Some((episode, format)) = formats.next().await {
+            match format {
+                Ok(format) => result.push(format),
+                Err(e) => report.failed(episode, e)?,
+            }
         }
 
         primary_season = false;
@@ -434,6 +929,54 @@ async fn formats_from_series(
     Ok(result)
 }
 
+async fn format_from_episode(
+    ctx: &Context,
+    archive: &Archive,
+    episode: &Media<Episode>,
+    season_episodes: &Vec<Media<Episode>>,
+    primary_season: bool,
+) -> Result<(Vec<Format>, Vec<Subtitle>)> {
+    let streams = cache::episode_streams(ctx, episode).await?;
+    let streaming_data = ctx
+        .retry
+        .api("streaming data", || streams.hls_streaming_data(None))
+        .await?;
+    let Some(stream) = find_resolution(streaming_data, &archive.resolution) else {
+        bail!(
+            "Resolution ({}x{}) is not available for episode {} ({}) of season {} ({}) of {}",
+            archive.resolution.width,
+            archive.resolution.height,
+            episode.metadata.episode_number,
+            episode.title,
+            episode.metadata.season_number,
+            episode.metadata.season_title,
+            episode.metadata.series_title
+        )
+    };
+
+    let mut formats: Vec<Format> = Vec::new();
+    let mut subtitles: Vec<Subtitle> = Vec::new();
+    subtitles.extend(archive.subtitle.iter().filter_map(|l| {
+        let stream_subtitle = streams.subtitles.get(l).cloned()?;
+        let subtitle = Subtitle {
+            stream_subtitle,
+            audio_locale: episode.metadata.audio_locale.clone(),
+            episode_id: episode.id.clone(),
+            forced: !episode.metadata.is_subbed,
+            primary: primary_season,
+        };
+        Some(subtitle)
+    }));
+    formats.push(Format::new_from_episode(
+        episode,
+        season_episodes,
+        stream,
+        vec![],
+    ));
+
+    Ok((formats, subtitles))
+}
+
 async fn download_video(ctx: &Context, format: &Format, only_audio: bool) -> Result<TempPath> {
     let tempfile = if only_audio {
         tempfile(".aac")?
@@ -442,43 +985,146 @@ async fn download_video(ctx: &Context, format: &Format, only_audio: bool) -> Res
     };
     let (_, path) = tempfile.into_parts();
 
-    let ffmpeg = Command::new("ffmpeg")
+    let mut args: Vec<String> = [
+        "-y", "-nostats", "-f", "mpegts", "-i", "pipe:", "-c", "copy",
+    ]
+    .into_iter()
+    .map(|s| s.to_string())
+    .collect();
+    if only_audio {
+        args.push("-vn".to_string())
+    }
+    args.push(path.to_string_lossy().to_string());
+    let mut ffmpeg = tokio::process::Command::from(ffmpeg_command(&args))
         .stdin(Stdio::piped())
         .stdout(Stdio::null())
         .stderr(Stdio::piped())
-        .arg("-y")
-        .args(["-f", "mpegts"])
-        .args(["-i", "pipe:"])
-        .args(["-c", "copy"])
-        .args(if only_audio { vec!["-vn"] } else { vec![] })
-        .arg(path.to_str().unwrap())
+        .kill_on_drop(true)
         .spawn()?;
+    let message = format!("Remux {}", format.audio);
+    ffmpeg_start_event("ffmpeg", &format.episode_id, &message);
+    let mut stderr = ffmpeg.stderr.take().unwrap();
+    // stderr must be read while ffmpeg is running, otherwise ffmpeg stops reading stdin if the
+    // pipe is full and the download blocks forever
+    let stderr_task = tokio::spawn(async move {
+        let mut buf = vec![];
+        let _ = stderr.read_to_end(&mut buf).await;
+        buf
+    });
 
+    let mut stdin = ffmpeg.stdin.take().unwrap();
     download_segments(
         ctx,
-        &mut ffmpeg.stdin.unwrap(),
-        Some(format!("Download {}", format.audio)),
-        format.stream.clone(),
+        &mut stdin,
+        // the progress bars of multiple episodes must be distinguishable
+        Some(if ctx.jobs > 1 {
+            format!(
+                "S{:02}E{:02} Download {}",
+                format.season_number, format.episode_number, format.audio
+            )
+        } else {
+            format!("Download {}", format.audio)
+        }),
+        format,
     )
     .await?;
+    // closing stdin tells ffmpeg that the input is complete. the file is only usable (e.g. to get
+    // its length) after ffmpeg has exited
+    drop(stdin);
+    let status = ffmpeg.wait().await?;
+    let stderr = stderr_task.await?;
+    ffmpeg_end_event("ffmpeg", &format.episode_id, &message, status.success());
+    if !status.success() {
+        bail!("{}", String::from_utf8_lossy(stderr.as_slice()))
+    }
 
     Ok(path)
 }
 
-fn generate_mkv(
+async fn generate_mkv(
     archive: &Archive,
     target: PathBuf,
-    video_paths: Vec<(TempPath, &Format)>,
-    audio_paths: Vec<(TempPath, &Format)>,
-    subtitle_paths: Vec<(TempPath, Subtitle)>,
+    downloaded: DownloadedEpisode,
 ) -> Result<()> {
+    let video_paths: Vec<(&Path, &Format)> =
+        downloaded.videos.iter().map(|(p, f)| (&**p, f)).collect();
+    let audio_paths: Vec<(&Path, &Format)> =
+        downloaded.audios.iter().map(|(p, f)| (&**p, f)).collect();
+    let subtitle_paths: Vec<(&Path, Subtitle)> = downloaded
+        .subtitles
+        .iter()
+        .map(|(p, s)| (&**p, s.clone()))
+        .collect();
+
+    debug!("Generating mkv {}", target.display());
+
+    // create parent directory if it does not exist
+    if let Some(parent) = target.parent() {
+        if !parent.exists() {
+            std::fs::create_dir_all(parent)?
+        }
+    }
+
+    let (_, primary) = video_paths.first().unwrap();
+    let message = format!(
+        "Generating mkv S{:02}E{:02}",
+        primary.season_number, primary.episode_number
+    );
+    match archive.muxer {
+        Muxer::Ffmpeg => {
+            let command_args = mkv_ffmpeg_args(
+                archive,
+                &target,
+                &video_paths,
+                &audio_paths,
+                &subtitle_paths,
+            );
+            run_ffmpeg(command_args, primary.duration, message, &primary.episode_id).await
+        }
+        Muxer::Mkvmerge => {
+            let command_args = mkv_mkvmerge_args(
+                archive,
+                &target,
+                &video_paths,
+                &audio_paths,
+                &subtitle_paths,
+            );
+            run_mkvmerge(command_args, message, &primary.episode_id).await
+        }
+    }
+}
+
+/// Title of a subtitle track. Subtitles which belong to another video than the primary one have
+/// the audio language of their video appended.
+fn subtitle_title(subtitle: &Subtitle) -> String {
+    subtitle.stream_subtitle.locale.to_human_readable()
+        + if !subtitle.primary {
+            format!(" [Video: {}]", subtitle.audio_locale.to_human_readable())
+        } else {
+            "".to_string()
+        }
+        .as_str()
+}
+
+/// Arguments which are passed to ffmpeg to merge all downloaded videos, audios and subtitles into
+/// one mkv file.
+fn mkv_ffmpeg_args<P: AsRef<Path>>(
+    archive: &Archive,
+    target: &Path,
+    video_paths: &[(P, &Format)],
+    audio_paths: &[(P, &Format)],
+    subtitle_paths: &[(P, Subtitle)],
+) -> Vec<String> {
     let mut input = vec![];
     let mut maps = vec![];
     let mut metadata = vec![];
     let mut dispositions = vec![vec![]; subtitle_paths.len()];
 
     for (i, (video_path, format)) in video_paths.iter().enumerate() {
-        input.extend(["-i".to_string(), video_path.to_string_lossy().to_string()]);
+        input.extend([
+            "-i".to_string(),
+            video_path.as_ref().to_string_lossy().to_string(),
+        ]);
         maps.extend(["-map".to_string(), i.to_string()]);
         metadata.extend([
             format!("-metadata:s:v:{}", i),
@@ -498,7 +1144,10 @@ fn generate_mkv(
         ]);
     }
     for (i, (audio_path, format)) in audio_paths.iter().enumerate() {
-        input.extend(["-i".to_string(), audio_path.to_string_lossy().to_string()]);
+        input.extend([
+            "-i".to_string(),
+            audio_path.as_ref().to_string_lossy().to_string(),
+        ]);
         maps.extend(["-map".to_string(), (i + video_paths.len()).to_string()]);
         metadata.extend([
             format!("-metadata:s:a:{}", i + video_paths.len()),
@@ -512,7 +1161,7 @@ fn generate_mkv(
     for (i, (subtitle_path, subtitle)) in subtitle_paths.iter().enumerate() {
         input.extend([
             "-i".to_string(),
-            subtitle_path.to_string_lossy().to_string(),
+            subtitle_path.as_ref().to_string_lossy().to_string(),
         ]);
         maps.extend([
             "-map".to_string(),
@@ -524,16 +1173,7 @@ fn generate_mkv(
         ]);
         metadata.extend([
             format!("-metadata:s:s:{}", i),
-            format!(
-                "title={}",
-                subtitle.stream_subtitle.locale.to_human_readable()
-                    + if !subtitle.primary {
-                        format!(" [Video: {}]", subtitle.audio_locale.to_human_readable())
-                    } else {
-                        "".to_string()
-                    }
-                    .as_str()
-            ),
+            format!("title={}", subtitle_title(subtitle)),
         ]);
 
         // mark forced subtitles
@@ -542,19 +1182,10 @@ fn generate_mkv(
         }
     }
 
-    let (input_presets, output_presets) = if let Some(preset) = archive.ffmpeg_preset.clone() {
-        preset.to_input_output_args()
-    } else {
-        (
-            vec![],
-            vec![
-                "-c:v".to_string(),
-                "copy".to_string(),
-                "-c:a".to_string(),
-                "copy".to_string(),
-            ],
-        )
-    };
+    let (input_presets, output_presets) = preset_args(
+        archive.ffmpeg_preset.as_ref(),
+        archive.audio_preset.as_ref(),
+    );
 
     let mut command_args = vec!["-y".to_string()];
     command_args.extend(input_presets);
@@ -596,23 +1227,94 @@ fn generate_mkv(
         target.to_string_lossy().to_string(),
     ]);
 
-    debug!("ffmpeg {}", command_args.join(" "));
+    command_args
+}
 
-    // create parent directory if it does not exist
-    if let Some(parent) = target.parent() {
-        if !parent.exists() {
-            std::fs::create_dir_all(parent)?
-        }
+/// Arguments which are passed to mkvmerge to merge all downloaded videos, audios and subtitles
+/// into one mkv file. The options of a file are placed before it and apply to its tracks, track id
+/// `-1` applies to every track of the file.
+fn mkv_mkvmerge_args<P: AsRef<Path>>(
+    archive: &Archive,
+    target: &Path,
+    video_paths: &[(P, &Format)],
+    audio_paths: &[(P, &Format)],
+    subtitle_paths: &[(P, Subtitle)],
+) -> Vec<String> {
+    let mut args = vec!["-o".to_string(), target.to_string_lossy().to_string()];
+
+    // only the tracks of the first video are default tracks, like ffmpeg does it
+    for (i, (path, format)) in video_paths.iter().chain(audio_paths.iter()).enumerate() {
+        args.extend([
+            "--language".to_string(),
+            format!("-1:{}", format.audio),
+            "--track-name".to_string(),
+            format!("-1:{}", format.audio.to_human_readable()),
+            "--default-track-flag".to_string(),
+            format!("-1:{}", if i == 0 { "yes" } else { "no" }),
+            path.as_ref().to_string_lossy().to_string(),
+        ]);
+    }
+    for (path, subtitle) in subtitle_paths {
+        let default = archive
+            .default_subtitle
+            .as_ref()
+            .is_some_and(|l| l == &subtitle.stream_subtitle.locale);
+        args.extend([
+            "--language".to_string(),
+            format!("0:{}", subtitle.stream_subtitle.locale),
+            "--track-name".to_string(),
+            format!("0:{}", subtitle_title(subtitle)),
+            "--default-track-flag".to_string(),
+            format!("0:{}", if default { "yes" } else { "no" }),
+            "--forced-display-flag".to_string(),
+            format!("0:{}", if subtitle.forced { "yes" } else { "no" }),
+            path.as_ref().to_string_lossy().to_string(),
+        ]);
     }
 
-    let ffmpeg = Command::new("ffmpeg")
-        .stdout(Stdio::null())
-        .stderr(Stdio::piped())
-        .args(command_args)
-        .output()?;
-    if !ffmpeg.status.success() {
-        bail!("{}", String::from_utf8_lossy(ffmpeg.stderr.as_slice()))
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn base() -> Archive {
+        Archive::try_parse_from([
+            "archive",
+            "--yes",
+            "-l",
+            "ja-JP",
+            "https://www.crunchyroll.com/series/GY8VEQ95Y",
+        ])
+        .unwrap()
+    }
+
+    fn line(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
     }
 
-    Ok(())
+    #[test]
+    fn batch_line_inherits_unset_args() {
+        let archive = base()
+            .merge_batch_line(line(&["url", "-o", "{title}.mkv"]))
+            .unwrap();
+        assert_eq!(archive.urls, vec!["url"]);
+        assert_eq!(archive.locale, vec![Locale::ja_JP]);
+        assert_eq!(archive.output, "{title}.mkv");
+        assert!(archive.yes)
+    }
+
+    #[test]
+    fn batch_line_rejects_run_args() {
+        for args in [
+            &["url", "--batch-file", "other.txt"][..],
+            &["url", "--continue-on-error"],
+            &["url", "--report", "report.json"],
+            &["-l", "de-DE"],
+        ] {
+            assert!(base().merge_batch_line(line(args)).is_err(), "{:?}", args)
+        }
+    }
 }
@@ -0,0 +1,36 @@
+use crate::utils::cache::cache_dir;
+use anyhow::{bail, Result};
+use log::info;
+use std::fs;
+
+#[derive(Debug, clap::Parser)]
+#[clap(about = "Manage the cache of series, season and episode metadata")]
+pub struct Cache {
+    #[clap(subcommand)]
+    command: CacheCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum CacheCommand {
+    #[clap(about = "Remove all cached metadata")]
+    Clear,
+}
+
+impl Cache {
+    /// Unlike the other commands, managing the cache does not need a login, so it's not executed
+    /// via [`crate::Execute`].
+    pub fn run(&self) -> Result<()> {
+        match self.command {
+            CacheCommand::Clear => {
+                let Some(dir) = cache_dir() else {
+                    bail!("Cannot find cache path")
+                };
+                if dir.exists() {
+                    fs::remove_dir_all(&dir)?;
+                }
+                info!("Cleared cache ({})", dir.to_string_lossy());
+                Ok(())
+            }
+        }
+    }
+}
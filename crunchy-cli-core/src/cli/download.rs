@@ -1,26 +1,35 @@
 use crate::cli::log::tab_info;
 use crate::cli::utils::{
-    download_segments, find_multiple_seasons_with_same_number, find_resolution,
-    interactive_season_choosing, FFmpegPreset,
+    check_disk_space, check_ffmpeg, download_segments, find_multiple_seasons_with_same_number,
+    find_resolution, interactive_season_choosing, preset_args, run_ffmpeg, run_pipeline,
+    AudioPreset, FFmpegPreset,
 };
+use crate::utils::batch::{inherit_unset_args, read_batch_file};
+use crate::utils::cache;
 use crate::utils::context::Context;
+use crate::utils::ffmpeg::{ffmpeg_command_line, has_ffmpeg};
 use crate::utils::format::Format;
 use crate::utils::log::progress;
-use crate::utils::os::{free_file, has_ffmpeg, is_special_file, tempfile};
+use crate::utils::os::{free_file, is_special_file, tempfile};
 use crate::utils::parse::{parse_url, UrlFilter};
+use crate::utils::probe::probe;
+use crate::utils::report::{Report, ReportItem};
 use crate::utils::sort::{sort_formats_after_seasons, sort_seasons_after_number};
 use crate::utils::subtitle::download_subtitle;
-use crate::utils::video::get_video_length;
 use crate::Execute;
-use anyhow::{bail, Result};
-use crunchyroll_rs::media::{Resolution, StreamSubtitle, VariantData};
+use anyhow::{anyhow, bail, Result};
+use clap::{CommandFactory, FromArgMatches};
+use crunchyroll_rs::media::Resolution;
 use crunchyroll_rs::{
     Episode, Locale, Media, MediaCollection, Movie, MovieListing, Season, Series,
 };
+use futures_util::{stream, StreamExt};
+use indicatif::HumanBytes;
 use log::{debug, error, info, warn};
 use std::borrow::Cow;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use tempfile::{NamedTempFile, TempPath};
 
 #[derive(Debug, clap::Parser)]
 #[clap(about = "Download a video")]
@@ -74,25 +83,128 @@ pub struct Download {
     #[arg(long)]
     #[arg(value_parser = FFmpegPreset::parse)]
     ffmpeg_preset: Option<FFmpegPreset>,
+    #[arg(help = format!("Preset for audio converting. \
+    Available presets: \n  {}", AudioPreset::available_matches_human_readable().join("\n  ")))]
+    #[arg(long_help = format!("Preset for audio converting. \
+    Every audio track is transcoded to the given codec, optionally with the given bitrate. \
+    Can be combined with '--ffmpeg-preset' and overrides its audio settings. \
+    Available presets: \n  {}", AudioPreset::available_matches_human_readable().join("\n  ")))]
+    #[arg(long)]
+    #[arg(value_parser = AudioPreset::parse)]
+    audio_preset: Option<AudioPreset>,
 
     #[arg(help = "Skip files which are already existing")]
     #[arg(long, default_value_t = false)]
     skip_existing: bool,
 
+    #[arg(help = "Only show what would be downloaded, without downloading anything")]
+    #[arg(
+        long_help = "Only show what would be downloaded, without downloading anything. \
+    Url filters, season choosing, resolution matching and file renaming are applied as usual. \
+    Prints the output path, selected stream, ffmpeg command and estimated size of every episode"
+    )]
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    #[arg(help = "Continue with the next episode if one fails and print a summary at the end")]
+    #[arg(
+        long_help = "Continue with the next episode if one fails and print a summary at the end. \
+    The summary lists all succeeded, skipped and failed episodes. If any episode failed, the exit code is non-zero"
+    )]
+    #[arg(long, default_value_t = false)]
+    continue_on_error: bool,
+
+    #[arg(help = "Write a json report of all processed episodes to the given file")]
+    #[arg(
+        long_help = "Write a json report of all processed episodes to the given file. \
+    It contains the url, episode id, output path, selected stream (resolution, fps, bandwidth), audio and subtitle languages, \
+    written bytes, duration, status and error message of every episode"
+    )]
+    #[arg(long)]
+    report: Option<PathBuf>,
+
     #[arg(help = "Ignore interactive input")]
     #[arg(short, long, default_value_t = false)]
     yes: bool,
 
+    #[arg(help = "File with url(s) to download, one per line. Use '-' to read from stdin")]
+    #[arg(
+        long_help = "File with url(s) to download, one per line. Use '-' to read from stdin (requires '--yes'). \
+    Every line can contain additional arguments which overwrite the ones given on the command line for this url only, \
+    e.g. `https://www.crunchyroll.com/series/12345678/example[S2] --audio de-DE -o \"{title}.mp4\"`. \
+    Empty lines and lines starting with '#' are ignored"
+    )]
+    #[arg(long)]
+    batch_file: Option<String>,
+
     #[arg(help = "Url(s) to Crunchyroll episodes or series")]
     urls: Vec<String>,
+
+    #[arg(skip)]
+    batch: Vec<Download>,
+}
+
+impl Download {
+    /// Create a new [`Download`] from the arguments of a batch file line. All arguments which are
+    /// not given in the line are taken from `self`.
+    fn merge_batch_line(&self, args: Vec<String>) -> Result<Download> {
+        let matches = Download::command()
+            .no_binary_name(true)
+            .try_get_matches_from(args)?;
+        let mut download = Download::from_arg_matches(&matches)?;
+
+        if download.batch_file.is_some() {
+            bail!("'--batch-file' cannot be used inside a batch file")
+        } else if download.continue_on_error {
+            // these apply to the whole run and not only to the urls of a single line
+            bail!("'--continue-on-error' cannot be used inside a batch file")
+        } else if download.report.is_some() {
+            bail!("'--report' cannot be used inside a batch file")
+        } else if download.urls.is_empty() {
+            bail!("No url given")
+        }
+
+        inherit_unset_args!(
+            self,
+            download,
+            matches,
+            [
+                audio,
+                subtitle,
+                output,
+                resolution,
+                ffmpeg_preset,
+                audio_preset,
+                skip_existing,
+                dry_run,
+                yes
+            ]
+        );
+
+        Ok(download)
+    }
+
+    /// If the downloaded segments can be written straight to the output file. This is the case if
+    /// the output is a `.ts` file, which is the format the segments are in, and nothing has to be
+    /// encoded or muxed. No ffmpeg is needed then.
+    fn is_raw(&self) -> bool {
+        self.ffmpeg_preset.is_none()
+            && self.audio_preset.is_none()
+            && self.subtitle.is_none()
+            && Path::new(&self.output).extension().unwrap_or_default() == "ts"
+    }
 }
 
 #[async_trait::async_trait(?Send)]
 impl Execute for Download {
     fn pre_check(&mut self) -> Result<()> {
-        if !has_ffmpeg() {
-            bail!("FFmpeg is needed to run this command")
-        } else if Path::new(&self.output)
+        if !self.dry_run && !self.is_raw() {
+            if !has_ffmpeg() {
+                bail!("FFmpeg is needed to run this command. Only '.ts' output without subtitles, ffmpeg and audio preset works without it")
+            }
+            check_ffmpeg(self.ffmpeg_preset.as_ref(), self.audio_preset.as_ref())?
+        }
+        if Path::new(&self.output)
             .extension()
             .unwrap_or_default()
             .is_empty()
@@ -109,29 +221,121 @@ impl Execute for Download {
             }
         }
 
+        if let Some(batch_file) = &self.batch_file {
+            for (line, args) in read_batch_file(batch_file, self.yes)? {
+                let mut download = self
+                    .merge_batch_line(args)
+                    .map_err(|e| anyhow!("line {} of batch file: {}", line, e))?;
+                download
+                    .pre_check()
+                    .map_err(|e| anyhow!("line {} of batch file: {}", line, e))?;
+                self.batch.push(download)
+            }
+        }
+
         Ok(())
     }
 
     async fn execute(self, ctx: Context) -> Result<()> {
+        let report = Report::new(self.continue_on_error);
+
+        let result: Result<()> = async {
+            let mut jobs = vec![(&self, self.resolve_formats(&ctx, &report).await?)];
+            for download in &self.batch {
+                jobs.push((download, download.resolve_formats(&ctx, &report).await?))
+            }
+
+            let mut temp_sizes = vec![];
+            let mut outputs = vec![];
+            for (download, formats) in &jobs {
+                // dry run lines of a batch file don't write anything. if the whole run is a dry
+                // run, the estimate is still shown
+                if download.dry_run && !self.dry_run {
+                    continue;
+                }
+                let (job_temp_sizes, job_outputs) = download.estimate_space(formats);
+                temp_sizes.extend(job_temp_sizes);
+                outputs.extend(job_outputs)
+            }
+            // the temporary files of all episodes which are downloading, waiting for ffmpeg or
+            // being converted exist together
+            temp_sizes.sort_unstable_by(|a, b| b.cmp(a));
+            let temp_size = temp_sizes
+                .into_iter()
+                .take(ctx.jobs * 2 + ctx.encode_jobs)
+                .sum();
+            check_disk_space(temp_size, outputs, self.yes, self.dry_run)?;
+
+            // output paths which are taken by an episode of this run. as the files are not created
+            // before the episode is downloaded (or never in a dry run) they are needed to detect
+            // collisions between the episodes of the run
+            let mut reserved_paths = HashSet::new();
+            let mut queue = vec![];
+            for (download, formats) in jobs {
+                for (url, format, path) in
+                    download.prepare_formats(&report, formats, &mut reserved_paths)
+                {
+                    queue.push((download, url, format, path))
+                }
+            }
+            download_queue(&ctx, &report, queue).await
+        }
+        .await;
+
+        if let Some(report_path) = &self.report {
+            if let Err(e) = report.write_json(report_path) {
+                if result.is_ok() {
+                    return Err(e);
+                }
+                // the error which made the run fail is more important, only log this one
+                error!("Failed to write report to {}: {}", report_path.display(), e)
+            }
+        }
+        result?;
+
+        if self.continue_on_error {
+            report.print_summary()
+        }
+        let failed_count = report.failed_count();
+        if failed_count > 0 {
+            bail!("{} item(s) failed", failed_count)
+        }
+
+        Ok(())
+    }
+}
+
+impl Download {
+    /// Resolve all urls to the formats which should be downloaded, together with the url they
+    /// belong to.
+    async fn resolve_formats(
+        &self,
+        ctx: &Context,
+        report: &Report,
+    ) -> Result<Vec<(String, Format)>> {
         let mut parsed_urls = vec![];
+        let mut queue = vec![];
 
         for (i, url) in self.urls.iter().enumerate() {
-            let progress_handler = progress!("Parsing url {}", i + 1);
-            match parse_url(&ctx.crunchy, url.clone(), true).await {
+            report.set_current_url(url);
+            let progress_handler = progress!(stage = "url_parse"; "Parsing url {}", i + 1);
+            match parse_url(ctx, url.clone(), true).await {
                 Ok((media_collection, url_filter)) => {
-                    parsed_urls.push((media_collection, url_filter));
-                    progress_handler.stop(format!("Parsed url {}", i + 1))
+                    parsed_urls.push((url, media_collection, url_filter));
+                    progress_handler.stop(format!("Parsed url {}", i + 1));
+                    info!(target: "event", event = "url_parsed", url = url.as_str(); "Parsed url {}", i + 1)
                 }
-                Err(e) => bail!("url {} could not be parsed: {}", url, e),
+                Err(e) => report.failed(url, anyhow!("url {} could not be parsed: {}", url, e))?,
             }
         }
 
-        for (i, (media_collection, url_filter)) in parsed_urls.into_iter().enumerate() {
-            let progress_handler = progress!("Fetching series details");
-            let formats = match media_collection {
+        for (i, (url, media_collection, url_filter)) in parsed_urls.into_iter().enumerate() {
+            report.set_current_url(url);
+            let progress_handler = progress!(stage = "fetch_details"; "Fetching series details");
+            let formats_result = match media_collection {
                 MediaCollection::Series(series) => {
                     debug!("Url {} is series ({})", i + 1, series.title);
-                    formats_from_series(&self, series, &url_filter).await?
+                    formats_from_series(ctx, self, series, &url_filter, report).await
                 }
                 MediaCollection::Season(season) => {
                     debug!(
@@ -140,7 +344,7 @@ impl Execute for Download {
                         season.metadata.season_number,
                         season.title
                     );
-                    formats_from_season(&self, season, &url_filter).await?
+                    formats_from_season(ctx, self, season, &url_filter, report).await
                 }
                 MediaCollection::Episode(episode) => {
                     debug!(
@@ -152,24 +356,34 @@ impl Execute for Download {
                         episode.metadata.season_title,
                         episode.metadata.series_title
                     );
-                    format_from_episode(&self, &episode, &url_filter, None, false)
-                        .await?
-                        .map(|fmt| vec![fmt])
+                    format_from_episode(ctx, self, &episode, &url_filter, None, false, report)
+                        .await
+                        .map(|fmt| fmt.map(|f| vec![f]))
                 }
                 MediaCollection::MovieListing(movie_listing) => {
                     debug!("Url {} is movie listing ({})", i + 1, movie_listing.title);
-                    format_from_movie_listing(&self, movie_listing, &url_filter).await?
+                    format_from_movie_listing(ctx, self, movie_listing, &url_filter, report).await
                 }
                 MediaCollection::Movie(movie) => {
                     debug!("Url {} is movie ({})", i + 1, movie.title);
-                    format_from_movie(&self, movie, &url_filter)
-                        .await?
-                        .map(|fmt| vec![fmt])
+                    format_from_movie(ctx, self, movie, &url_filter, report)
+                        .await
+                        .map(|fmt| fmt.map(|f| vec![f]))
+                }
+            };
+            let formats = match formats_result {
+                Ok(formats) => formats,
+                Err(e) => {
+                    report.failed(url, e)?;
+                    continue;
                 }
             };
 
             let Some(formats) = formats else {
-                progress_handler.stop(format!("Skipping url {} (no matching episodes found)", i + 1));
+                progress_handler.stop(format!(
+                    "Skipping url {} (no matching episodes found)",
+                    i + 1
+                ));
                 continue;
             };
             progress_handler.stop(format!("Loaded series information for url {}", i + 1));
@@ -212,78 +426,198 @@ impl Execute for Download {
                 }
             }
 
-            for format in formats {
-                let formatted_path = format.format_path((&self.output).into(), true);
-                let (path, changed) = free_file(formatted_path.clone());
+            queue.extend(formats.into_iter().map(|f| (url.clone(), f)))
+        }
 
-                if changed && self.skip_existing {
-                    debug!(
-                        "Skipping already existing file '{}'",
-                        formatted_path.to_string_lossy()
-                    );
-                    continue;
+        Ok(queue)
+    }
+
+    /// Estimated space which is needed to download the given formats. Returns the space every
+    /// format needs in the temp directory (temporary files are removed after every episode) and
+    /// the estimated size of every output file.
+    fn estimate_space(&self, formats: &[(String, Format)]) -> (Vec<u64>, Vec<(PathBuf, u64)>) {
+        let mut temp_sizes = vec![];
+        let mut outputs = vec![];
+
+        for (_, format) in formats {
+            let path = format.format_path((&self.output).into(), true);
+            if self.skip_existing && path.exists() {
+                continue;
+            }
+
+            let estimated_size = format.estimated_size();
+            if path.to_string_lossy() == "-" {
+                // the output is written to a temporary file first before it gets written to stdout
+                temp_sizes.push(estimated_size * 2)
+            } else {
+                // raw downloads are written straight to the output file
+                temp_sizes.push(if self.is_raw() { 0 } else { estimated_size });
+                if !is_special_file(&path) {
+                    outputs.push((path, estimated_size))
                 }
+            }
+        }
 
-                info!(
-                    "Downloading {} to '{}'",
-                    format.title,
-                    if is_special_file(&path) {
-                        path.to_str().unwrap()
-                    } else {
-                        path.file_name().unwrap().to_str().unwrap()
-                    }
-                );
-                tab_info!(
-                    "Episode: S{:02}E{:02}",
-                    format.season_number,
-                    format.episode_number
-                );
-                tab_info!("Audio: {}", format.audio);
-                tab_info!(
-                    "Subtitles: {}",
-                    self.subtitle
-                        .clone()
-                        .map_or("None".to_string(), |l| l.to_string())
+        (temp_sizes, outputs)
+    }
+
+    /// Check which of the formats should be downloaded and to which path. Formats which are
+    /// skipped (or only printed in a dry run) are reported directly, all others are returned
+    /// together with the url they belong to and their output path.
+    fn prepare_formats(
+        &self,
+        report: &Report,
+        formats: Vec<(String, Format)>,
+        reserved_paths: &mut HashSet<PathBuf>,
+    ) -> Vec<(String, Format, PathBuf)> {
+        let mut prepared = vec![];
+
+        for (url, format) in formats {
+            report.set_current_url(&url);
+            let formatted_path = format.format_path((&self.output).into(), true);
+            let (path, changed) = free_file(formatted_path.clone(), reserved_paths);
+
+            if changed && self.skip_existing {
+                debug!(
+                    "Skipping already existing file '{}'",
+                    formatted_path.to_string_lossy()
                 );
-                tab_info!("Resolution: {}", format.stream.resolution);
-                tab_info!("FPS: {:.2}", format.stream.fps);
-
-                download_ffmpeg(
-                    &ctx,
-                    &self,
-                    format.stream,
-                    format.subtitles.get(0).cloned(),
-                    path.to_path_buf(),
-                )
-                .await?;
+                report.skipped(&format, "file already exists");
+                continue;
             }
+
+            if self.dry_run {
+                self.print_format(&format, &path);
+
+                tab_info!("Estimated size: {}", HumanBytes(format.estimated_size()));
+                if self.is_raw() {
+                    tab_info!(
+                        "FFmpeg: not needed, the segments are written straight to the output file"
+                    )
+                } else {
+                    let target = if path.to_string_lossy() == "-" {
+                        PathBuf::from("<stdout>.mp4")
+                    } else {
+                        path.clone()
+                    };
+                    let args = ffmpeg_args(
+                        self,
+                        Path::new("<video>.ts"),
+                        format
+                            .subtitles
+                            .first()
+                            .map(|_| Path::new("<subtitle>.ass")),
+                        &target,
+                    );
+                    tab_info!("FFmpeg: {}", ffmpeg_command_line(&args));
+                }
+                report.skipped(ReportItem::from(&format).output(&path), "dry run");
+                reserved_paths.insert(path);
+                continue;
+            }
+
+            reserved_paths.insert(path.clone());
+            prepared.push((url, format, path))
         }
 
-        Ok(())
+        prepared
+    }
+
+    /// Print the selected format. It's printed as single message, as multiple episodes may be
+    /// printed at the same time (`--jobs`) and their lines must not mix.
+    fn print_format(&self, format: &Format, path: &Path) {
+        let details = [
+            format!(
+                "Episode: S{:02}E{:02}",
+                format.season_number, format.episode_number
+            ),
+            format!("Audio: {}", format.audio),
+            format!(
+                "Subtitles: {}",
+                self.subtitle
+                    .clone()
+                    .map_or("None".to_string(), |l| l.to_string())
+            ),
+            format!("Resolution: {}", format.stream.resolution),
+            format!("FPS: {:.2}", format.stream.fps),
+        ];
+
+        info!(
+            event = "format_selected",
+            episode_id = format.episode_id.as_str(),
+            output:% = path.display(),
+            resolution:% = format.stream.resolution,
+            fps = format.stream.fps,
+            bandwidth = format.stream.bandwidth,
+            audio:% = format.audio;
+            "{} {} to '{}'\n\t{}",
+            if self.dry_run { "Would download" } else { "Downloading" },
+            format.title,
+            if is_special_file(path) {
+                path.to_str().unwrap()
+            } else {
+                path.file_name().unwrap().to_str().unwrap()
+            },
+            details.join("\n\t")
+        );
     }
 }
 
-async fn download_ffmpeg(
+/// Download all queued formats. Downloading and generating the output file with ffmpeg run as
+/// separate stages (see [`run_pipeline`]), so the next format is already downloaded while ffmpeg
+/// converts the previous one. A format is announced when its download starts and its result is
+/// reported in the order of the queue, so the output does not depend on which one finishes first.
+async fn download_queue(
     ctx: &Context,
-    download: &Download,
-    variant_data: VariantData,
-    subtitle: Option<StreamSubtitle>,
-    mut target: PathBuf,
+    report: &Report,
+    queue: Vec<(&Download, String, Format, PathBuf)>,
 ) -> Result<()> {
-    let (input_presets, mut output_presets) = if let Some(preset) = download.ffmpeg_preset.clone() {
-        preset.to_input_output_args()
-    } else {
-        (
-            vec![],
-            vec![
-                "-c:v".to_string(),
-                "copy".to_string(),
-                "-c:a".to_string(),
-                "copy".to_string(),
-            ],
-        )
-    };
+    let queue = &queue;
+    run_pipeline(
+        ctx,
+        queue.len(),
+        |i| async move {
+            let (download, _, format, path) = &queue[i];
+            download.print_format(format, path);
+            download_episode(ctx, format.clone(), path, download.is_raw()).await
+        },
+        |i, downloaded| async move {
+            let (download, _, format, path) = &queue[i];
+            generate_output(download, format, downloaded, path.clone()).await
+        },
+        |i, duration, result| {
+            let (_, url, format, path) = &queue[i];
+            report.set_current_url(url);
+            let item = ReportItem::from(format).output(path).duration(duration);
+            match result {
+                Ok(_) => {
+                    info!(target: "event", event = "file_finished", path:% = path.display(); "Downloaded {}", format.title);
+                    report.succeeded(item);
+                    Ok(())
+                }
+                Err(e) => report.failed(item, e),
+            }
+        },
+    )
+    .await
+}
 
+enum DownloadedEpisode {
+    /// Files of a downloaded format which are waiting to be converted by ffmpeg.
+    Temporary {
+        video_file: NamedTempFile,
+        subtitle_file: Option<TempPath>,
+    },
+    /// The segments are already written to the output file, nothing is left to do.
+    Raw,
+}
+
+async fn download_episode(
+    ctx: &Context,
+    mut format: Format,
+    target: &Path,
+    raw: bool,
+) -> Result<DownloadedEpisode> {
     // create parent directory if it does not exist
     if let Some(parent) = target.parent() {
         if !parent.exists() {
@@ -291,15 +625,63 @@ async fn download_ffmpeg(
         }
     }
 
-    let mut video_file = tempfile(".ts")?;
-    download_segments(ctx, &mut video_file, None, variant_data).await?;
-    let subtitle_file = if let Some(ref sub) = subtitle {
-        let video_len = get_video_length(video_file.path().to_path_buf())?;
-        Some(download_subtitle(sub.clone(), video_len).await?)
+    // all formats are resolved before the first download starts. the stream urls of this one may
+    // have expired in the meantime, so they are fetched again right before they are needed, unless
+    // they were resolved only shortly before
+    if !format.is_stream_fresh() {
+        format.refresh_stream(ctx).await?;
+    }
+
+    // the progress bars of multiple episodes must be distinguishable
+    let message = (ctx.jobs > 1)
+        .then(|| format!("S{:02}E{:02}", format.season_number, format.episode_number));
+
+    if raw {
+        let mut file = tokio::fs::File::create(target).await?;
+        if let Err(e) = download_segments(ctx, &mut file, message, &format).await {
+            // don't leave a partial file behind, it would be skipped by `--skip-existing`
+            drop(file);
+            let _ = std::fs::remove_file(target);
+            return Err(e);
+        }
+        return Ok(DownloadedEpisode::Raw);
+    }
+
+    let video_file = tempfile(".ts")?;
+    download_segments(
+        ctx,
+        &mut tokio::fs::File::from_std(video_file.reopen()?),
+        message,
+        &format,
+    )
+    .await?;
+    let subtitle_file = if let Some(sub) = format.subtitles.first() {
+        let video_len = probe(video_file.path())?.length();
+        Some(download_subtitle(ctx, sub.clone(), video_len).await?)
     } else {
         None
     };
 
+    Ok(DownloadedEpisode::Temporary {
+        video_file,
+        subtitle_file,
+    })
+}
+
+async fn generate_output(
+    download: &Download,
+    format: &Format,
+    downloaded: DownloadedEpisode,
+    mut target: PathBuf,
+) -> Result<()> {
+    let DownloadedEpisode::Temporary {
+        video_file,
+        subtitle_file,
+    } = downloaded
+    else {
+        return Ok(());
+    };
+
     let stdout_tempfile = if target.to_string_lossy() == "-" {
         let file = tempfile(".mp4")?;
         target = file.path().to_path_buf();
@@ -309,7 +691,46 @@ async fn download_ffmpeg(
         None
     };
 
-    let subtitle_presets = if let Some(sub_file) = &subtitle_file {
+    debug!("Generating output file {}", target.display());
+    run_ffmpeg(
+        ffmpeg_args(
+            download,
+            video_file.path(),
+            subtitle_file.as_deref(),
+            &target,
+        ),
+        format.duration,
+        format!(
+            "Generating S{:02}E{:02}",
+            format.season_number, format.episode_number
+        ),
+        &format.episode_id,
+    )
+    .await?;
+
+    if let Some(mut stdout_file) = stdout_tempfile {
+        let mut stdout = std::io::stdout();
+
+        std::io::copy(&mut stdout_file, &mut stdout)?;
+    }
+
+    Ok(())
+}
+
+/// Arguments which are passed to ffmpeg to generate the output file from the downloaded video and
+/// (optional) subtitle file.
+fn ffmpeg_args(
+    download: &Download,
+    video_path: &Path,
+    subtitle_path: Option<&Path>,
+    target: &Path,
+) -> Vec<String> {
+    let (input_presets, mut output_presets) = preset_args(
+        download.ffmpeg_preset.as_ref(),
+        download.audio_preset.as_ref(),
+    );
+
+    let subtitle_presets = if let Some(sub_file) = subtitle_path {
         if target.extension().unwrap_or_default().to_string_lossy() == "mp4" {
             vec![
                 "-i".to_string(),
@@ -346,36 +767,21 @@ async fn download_ffmpeg(
         vec![]
     };
 
-    let mut ffmpeg = Command::new("ffmpeg")
-        .stdout(Stdio::null())
-        .stderr(Stdio::piped())
-        .arg("-y")
-        .args(input_presets)
-        .args(["-i", video_file.path().to_string_lossy().as_ref()])
-        .args(subtitle_presets)
-        .args(output_presets)
-        .arg(target.to_str().unwrap())
-        .spawn()?;
-
-    let progress_handler = progress!("Generating output file");
-    if !ffmpeg.wait()?.success() {
-        bail!("{}", std::io::read_to_string(ffmpeg.stderr.unwrap())?)
-    }
-    progress_handler.stop("Output file generated");
-
-    if let Some(mut stdout_file) = stdout_tempfile {
-        let mut stdout = std::io::stdout();
-
-        std::io::copy(&mut stdout_file, &mut stdout)?;
-    }
-
-    Ok(())
+    let mut args = vec!["-y".to_string()];
+    args.extend(input_presets);
+    args.extend(["-i".to_string(), video_path.to_string_lossy().to_string()]);
+    args.extend(subtitle_presets);
+    args.extend(output_presets);
+    args.push(target.to_string_lossy().to_string());
+    args
 }
 
 async fn formats_from_series(
+    ctx: &Context,
     download: &Download,
     series: Media<Series>,
     url_filter: &UrlFilter,
+    report: &Report,
 ) -> Result<Option<Vec<Format>>> {
     if !series.metadata.audio_locales.is_empty()
         && !series.metadata.audio_locales.contains(&download.audio)
@@ -387,7 +793,7 @@ async fn formats_from_series(
         return Ok(None);
     }
 
-    let mut seasons = series.seasons().await?;
+    let mut seasons = cache::seasons(ctx, &series).await?;
 
     // filter any season out which does not contain the specified audio language
     for season in sort_seasons_after_number(seasons.clone()) {
@@ -422,8 +828,11 @@ async fn formats_from_series(
 
     let mut formats = vec![];
     for season in seasons {
-        if let Some(fmts) = formats_from_season(download, season, url_filter).await? {
-            formats.extend(fmts)
+        let season_title = season.title.clone();
+        match formats_from_season(ctx, download, season, url_filter, report).await {
+            Ok(Some(fmts)) => formats.extend(fmts),
+            Ok(None) => (),
+            Err(e) => report.failed(format!("{} {}", series.title, season_title), e)?,
         }
     }
 
@@ -431,9 +840,11 @@ async fn formats_from_series(
 }
 
 async fn formats_from_season(
+    ctx: &Context,
     download: &Download,
     season: Media<Season>,
     url_filter: &UrlFilter,
+    report: &Report,
 ) -> Result<Option<Vec<Format>>> {
     if !url_filter.is_season_valid(season.metadata.season_number) {
         return Ok(None);
@@ -447,12 +858,30 @@ async fn formats_from_season(
 
     let mut formats = vec![];
 
-    let episodes = season.episodes().await?;
-    for episode in episodes.iter() {
-        if let Some(fmt) =
-            format_from_episode(download, &episode, url_filter, Some(&episodes), true).await?
-        {
-            formats.push(fmt)
+    let episodes = cache::episodes(ctx, &season).await?;
+    // the episodes are resolved concurrently but their results are returned in the order of the
+    // season
+    let season_episodes = &episodes;
+    let mut results = stream::iter(episodes.iter())
+        .map(|episode| async move {
+            let result = format_from_episode(
+                ctx,
+                download,
+                episode,
+                url_filter,
+                Some(season_episodes),
+                true,
+                report,
+            )
+            .await;
+            (episode, result)
+        })
+        .buffered(ctx.concurrent_requests);
+    while let Some((episode, result)) = results.next().await {
+        match result {
+            Ok(Some(fmt)) => formats.push(fmt),
+            Ok(None) => (),
+            Err(e) => report.failed(episode, e)?,
         }
     }
 
@@ -460,11 +889,13 @@ async fn formats_from_season(
 }
 
 async fn format_from_episode(
+    ctx: &Context,
     download: &Download,
     episode: &Media<Episode>,
     url_filter: &UrlFilter,
     season_episodes: Option<&Vec<Media<Episode>>>,
     filter_audio: bool,
+    report: &Report,
 ) -> Result<Option<Format>> {
     if filter_audio && episode.metadata.audio_locale != download.audio {
         error!(
@@ -476,6 +907,7 @@ async fn format_from_episode(
             episode.metadata.series_title,
             download.audio
         );
+        report.skipped(episode, format!("no {} audio", download.audio));
         return Ok(None);
     } else if !url_filter.is_episode_valid(
         episode.metadata.episode_number,
@@ -484,8 +916,11 @@ async fn format_from_episode(
         return Ok(None);
     }
 
-    let streams = episode.streams().await?;
-    let streaming_data = streams.hls_streaming_data(None).await?;
+    let streams = cache::episode_streams(ctx, episode).await?;
+    let streaming_data = ctx
+        .retry
+        .api("streaming data", || streams.hls_streaming_data(None))
+        .await?;
     let subtitle = if let Some(subtitle) = &download.subtitle {
         if let Some(sub) = streams.subtitles.get(subtitle) {
             Some(sub.clone())
@@ -499,6 +934,7 @@ async fn format_from_episode(
                 episode.metadata.series_title,
                 subtitle
             );
+            report.skipped(episode, format!("no {} subtitles", subtitle));
             return Ok(None);
         }
     } else {
@@ -522,7 +958,8 @@ async fn format_from_episode(
         if let Some(eps) = season_episodes {
             Cow::from(eps)
         } else {
-            Cow::from(episode.season().await?.episodes().await?)
+            let season = cache::season(ctx, episode).await?;
+            Cow::from(cache::episodes(ctx, &season).await?)
         }
     } else {
         Cow::from(vec![])
@@ -537,15 +974,27 @@ async fn format_from_episode(
 }
 
 async fn format_from_movie_listing(
+    ctx: &Context,
     download: &Download,
     movie_listing: Media<MovieListing>,
     url_filter: &UrlFilter,
+    report: &Report,
 ) -> Result<Option<Vec<Format>>> {
     let mut formats = vec![];
 
-    for movie in movie_listing.movies().await? {
-        if let Some(fmt) = format_from_movie(download, movie, url_filter).await? {
-            formats.push(fmt)
+    let movies = ctx.retry.api("movies", || movie_listing.movies()).await?;
+    let mut results = stream::iter(movies)
+        .map(|movie| async {
+            let movie_title = movie.title.clone();
+            let result = format_from_movie(ctx, download, movie, url_filter, report).await;
+            (movie_title, result)
+        })
+        .buffered(ctx.concurrent_requests);
+    while let Some((movie_title, result)) = results.next().await {
+        match result {
+            Ok(Some(fmt)) => formats.push(fmt),
+            Ok(None) => (),
+            Err(e) => report.failed(movie_title, e)?,
         }
     }
 
@@ -553,19 +1002,28 @@ async fn format_from_movie_listing(
 }
 
 async fn format_from_movie(
+    ctx: &Context,
     download: &Download,
     movie: Media<Movie>,
     _: &UrlFilter,
+    report: &Report,
 ) -> Result<Option<Format>> {
-    let streams = movie.streams().await?;
+    let streams = ctx.retry.api("streams", || movie.streams()).await?;
     let mut streaming_data = if let Some(subtitle) = &download.subtitle {
         if !streams.subtitles.keys().cloned().any(|x| &x == subtitle) {
             error!("Movie {} has no {} subtitles", movie.title, subtitle);
+            report.skipped(&movie.title, format!("no {} subtitles", subtitle));
             return Ok(None);
         }
-        streams.hls_streaming_data(Some(subtitle.clone())).await?
+        ctx.retry
+            .api("streaming data", || {
+                streams.hls_streaming_data(Some(subtitle.clone()))
+            })
+            .await?
     } else {
-        streams.hls_streaming_data(None).await?
+        ctx.retry
+            .api("streaming data", || streams.hls_streaming_data(None))
+            .await?
     };
 
     streaming_data.sort_by(|a, b| a.resolution.width.cmp(&b.resolution.width).reverse());
@@ -591,7 +1049,11 @@ async fn format_from_movie(
         }
     };
 
-    Ok(Some(Format::new_from_movie(&movie, stream)))
+    Ok(Some(Format::new_from_movie(
+        &movie,
+        stream,
+        download.subtitle.clone(),
+    )))
 }
 
 fn some_vec_or_none<T>(v: Vec<T>) -> Option<Vec<T>> {
@@ -601,3 +1063,62 @@ fn some_vec_or_none<T>(v: Vec<T>) -> Option<Vec<T>> {
         Some(v)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn base() -> Download {
+        Download::try_parse_from([
+            "download",
+            "--yes",
+            "-o",
+            "{title}.mp4",
+            "https://www.crunchyroll.com/watch/GRDQPM1ZY",
+        ])
+        .unwrap()
+    }
+
+    fn line(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn batch_line_inherits_unset_args() {
+        let download = base()
+            .merge_batch_line(line(&[
+                "https://www.crunchyroll.com/series/GY8VEQ95Y",
+                "-a",
+                "de-DE",
+            ]))
+            .unwrap();
+        assert_eq!(
+            download.urls,
+            vec!["https://www.crunchyroll.com/series/GY8VEQ95Y"]
+        );
+        assert_eq!(download.audio, Locale::de_DE);
+        assert_eq!(download.output, "{title}.mp4");
+        assert!(download.yes)
+    }
+
+    #[test]
+    fn batch_line_overwrites_args() {
+        let download = base()
+            .merge_batch_line(line(&["url", "-o", "{title}.mkv"]))
+            .unwrap();
+        assert_eq!(download.output, "{title}.mkv")
+    }
+
+    #[test]
+    fn batch_line_rejects_run_args() {
+        for args in [
+            &["url", "--batch-file", "other.txt"][..],
+            &["url", "--continue-on-error"],
+            &["url", "--report", "report.json"],
+            &["-a", "de-DE"],
+        ] {
+            assert!(base().merge_batch_line(line(args)).is_err(), "{:?}", args)
+        }
+    }
+}
@@ -0,0 +1,365 @@
+use crate::cli::log::tab_info;
+use crate::cli::utils::find_multiple_seasons_with_same_number;
+use crate::utils::cache;
+use crate::utils::context::Context;
+use crate::utils::log::progress;
+use crate::utils::parse::{parse_url, UrlFilter};
+use crate::utils::sort::sort_seasons_after_number;
+use crate::Execute;
+use anyhow::{anyhow, Result};
+use crunchyroll_rs::media::{VariantData, VideoStream};
+use crunchyroll_rs::{Episode, Media, MediaCollection, Movie, Season, Series};
+use log::{debug, info};
+use serde::Serialize;
+
+#[derive(Debug, clap::Parser)]
+#[clap(about = "Show the seasons and episodes behind a url without downloading anything")]
+#[command(arg_required_else_help(true))]
+pub struct Info {
+    #[arg(help = "Print the information as json")]
+    #[arg(
+        long_help = "Print the information as json. Use it together with '-q' to get only the json on stdout"
+    )]
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    #[arg(help = "Url(s) to Crunchyroll episodes or series")]
+    urls: Vec<String>,
+}
+
+#[async_trait::async_trait(?Send)]
+impl Execute for Info {
+    async fn execute(self, ctx: Context) -> Result<()> {
+        let mut parsed_urls = vec![];
+
+        for (i, url) in self.urls.iter().enumerate() {
+            let progress_handler = progress!(stage = "url_parse"; "Parsing url {}", i + 1);
+            match parse_url(&ctx, url.clone(), true).await {
+                Ok((media_collection, url_filter)) => {
+                    parsed_urls.push((url, media_collection, url_filter));
+                    progress_handler.stop(format!("Parsed url {}", i + 1))
+                }
+                Err(e) => return Err(anyhow!("url {} could not be parsed: {}", url, e)),
+            }
+        }
+
+        let mut infos = vec![];
+        for (i, (url, media_collection, url_filter)) in parsed_urls.into_iter().enumerate() {
+            let progress_handler =
+                progress!(stage = "fetch_details"; "Fetching details for url {}", i + 1);
+            let info = match media_collection {
+                MediaCollection::Series(series) => {
+                    debug!("Url {} is series ({})", i + 1, series.title);
+                    info_from_series(&ctx, url, series, &url_filter).await?
+                }
+                MediaCollection::Season(season) => {
+                    debug!(
+                        "Url {} is season {} ({})",
+                        i + 1,
+                        season.metadata.season_number,
+                        season.title
+                    );
+                    MediaInfo {
+                        url: url.clone(),
+                        id: season.id.clone(),
+                        title: season.title.clone(),
+                        seasons: vec![info_from_season(&ctx, season, &url_filter, false).await?],
+                        movies: vec![],
+                    }
+                }
+                MediaCollection::Episode(episode) => {
+                    debug!(
+                        "Url {} is episode {} ({}) of season {} ({}) of {}",
+                        i + 1,
+                        episode.metadata.episode_number,
+                        episode.title,
+                        episode.metadata.season_number,
+                        episode.metadata.season_title,
+                        episode.metadata.series_title
+                    );
+                    let season = cache::season(&ctx, &episode).await?;
+                    MediaInfo {
+                        url: url.clone(),
+                        id: episode.metadata.series_id.clone(),
+                        title: episode.metadata.series_title.clone(),
+                        seasons: vec![SeasonInfo {
+                            episodes: vec![EpisodeInfo::from_episode(&ctx, &episode).await?],
+                            ..SeasonInfo::from(&season)
+                        }],
+                        movies: vec![],
+                    }
+                }
+                MediaCollection::MovieListing(movie_listing) => {
+                    debug!("Url {} is movie listing ({})", i + 1, movie_listing.title);
+                    let mut movies = vec![];
+                    for movie in ctx.retry.api("movies", || movie_listing.movies()).await? {
+                        movies.push(EpisodeInfo::from_movie(&ctx, &movie).await?)
+                    }
+                    MediaInfo {
+                        url: url.clone(),
+                        id: movie_listing.id.clone(),
+                        title: movie_listing.title.clone(),
+                        seasons: vec![],
+                        movies,
+                    }
+                }
+                MediaCollection::Movie(movie) => {
+                    debug!("Url {} is movie ({})", i + 1, movie.title);
+                    MediaInfo {
+                        url: url.clone(),
+                        id: movie.metadata.movie_listing_id.clone(),
+                        title: movie.metadata.movie_listing_title.clone(),
+                        seasons: vec![],
+                        movies: vec![EpisodeInfo::from_movie(&ctx, &movie).await?],
+                    }
+                }
+            };
+            progress_handler.stop(format!("Fetched details for url {}", i + 1));
+            infos.push(info)
+        }
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&infos)?)
+        } else {
+            for info in infos {
+                info.print()
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Information about everything a url points to. Seasons are sorted after their season number,
+/// movies are kept in the order Crunchyroll returns them.
+#[derive(Serialize)]
+struct MediaInfo {
+    url: String,
+    id: String,
+    title: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    seasons: Vec<SeasonInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    movies: Vec<EpisodeInfo>,
+}
+
+impl MediaInfo {
+    fn print(&self) {
+        info!("{} ({})", self.title, self.url);
+        for season in &self.seasons {
+            tab_info!(
+                "Season {} ({}) » audio: {}, subtitles: {}{}",
+                season.season_number,
+                season.title,
+                join_or_none(&season.audio_locales),
+                join_or_none(&season.subtitle_locales),
+                if season.duplicated {
+                    " [duplicated season number]"
+                } else {
+                    ""
+                }
+            );
+            for episode in &season.episodes {
+                episode.print()
+            }
+        }
+        for movie in &self.movies {
+            movie.print()
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SeasonInfo {
+    id: String,
+    title: String,
+    season_number: u32,
+    audio_locales: Vec<String>,
+    subtitle_locales: Vec<String>,
+    /// If another season with the same season number but different content exists (see
+    /// [`find_multiple_seasons_with_same_number`]).
+    duplicated: bool,
+    episodes: Vec<EpisodeInfo>,
+}
+
+impl From<&Media<Season>> for SeasonInfo {
+    fn from(season: &Media<Season>) -> Self {
+        Self {
+            id: season.id.clone(),
+            title: season.title.clone(),
+            season_number: season.metadata.season_number,
+            audio_locales: season
+                .metadata
+                .audio_locales
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+            subtitle_locales: season
+                .metadata
+                .subtitle_locales
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+            duplicated: false,
+            episodes: vec![],
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct EpisodeInfo {
+    id: String,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    episode_number: Option<u32>,
+    audio: String,
+    subtitles: Vec<String>,
+    variants: Vec<VariantInfo>,
+}
+
+impl EpisodeInfo {
+    async fn from_episode(ctx: &Context, episode: &Media<Episode>) -> Result<Self> {
+        let streams = cache::episode_streams(ctx, episode).await?;
+        let mut info = Self::from_streams(ctx, &episode.id, &episode.title, &streams).await?;
+        info.episode_number = Some(episode.metadata.episode_number);
+        Ok(info)
+    }
+
+    async fn from_movie(ctx: &Context, movie: &Media<Movie>) -> Result<Self> {
+        let streams = ctx.retry.api("streams", || movie.streams()).await?;
+        Self::from_streams(ctx, &movie.id, &movie.title, &streams).await
+    }
+
+    async fn from_streams(
+        ctx: &Context,
+        id: &str,
+        title: &str,
+        streams: &VideoStream,
+    ) -> Result<Self> {
+        let mut subtitles: Vec<String> = streams.subtitles.keys().map(|l| l.to_string()).collect();
+        subtitles.sort();
+
+        let mut variants = ctx
+            .retry
+            .api("streaming data", || streams.hls_streaming_data(None))
+            .await?;
+        variants.sort_by(|a, b| a.resolution.width.cmp(&b.resolution.width).reverse());
+
+        Ok(Self {
+            id: id.to_string(),
+            title: title.to_string(),
+            episode_number: None,
+            audio: streams.audio_locale.to_string(),
+            subtitles,
+            variants: variants.iter().map(VariantInfo::from).collect(),
+        })
+    }
+
+    fn print(&self) {
+        if let Some(episode_number) = self.episode_number {
+            tab_info!(
+                "\tE{:02} {} » audio: {}, subtitles: {}",
+                episode_number,
+                self.title,
+                self.audio,
+                join_or_none(&self.subtitles)
+            )
+        } else {
+            tab_info!(
+                "{} » audio: {}, subtitles: {}",
+                self.title,
+                self.audio,
+                join_or_none(&self.subtitles)
+            )
+        }
+        let indent = if self.episode_number.is_some() {
+            "\t\t"
+        } else {
+            "\t"
+        };
+        for variant in &self.variants {
+            tab_info!(
+                "{}{}px, {:.2} FPS, {} kbit/s",
+                indent,
+                variant.resolution,
+                variant.fps,
+                variant.bandwidth / 1024
+            )
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VariantInfo {
+    resolution: String,
+    fps: f64,
+    bandwidth: u64,
+    codecs: String,
+}
+
+impl From<&VariantData> for VariantInfo {
+    fn from(variant: &VariantData) -> Self {
+        Self {
+            resolution: variant.resolution.to_string(),
+            fps: variant.fps,
+            bandwidth: variant.bandwidth,
+            codecs: variant.codecs.clone(),
+        }
+    }
+}
+
+async fn info_from_series(
+    ctx: &Context,
+    url: &str,
+    series: Media<Series>,
+    url_filter: &UrlFilter,
+) -> Result<MediaInfo> {
+    let mut seasons = cache::seasons(ctx, &series).await?;
+    seasons.retain(|s| url_filter.is_season_valid(s.metadata.season_number));
+    let duplicated = find_multiple_seasons_with_same_number(&seasons);
+
+    let mut season_infos = vec![];
+    for season in sort_seasons_after_number(seasons).into_iter().flatten() {
+        let is_duplicated = duplicated.contains(&season.metadata.season_number);
+        season_infos.push(info_from_season(ctx, season, url_filter, is_duplicated).await?)
+    }
+
+    Ok(MediaInfo {
+        url: url.to_string(),
+        id: series.id.clone(),
+        title: series.title.clone(),
+        seasons: season_infos,
+        movies: vec![],
+    })
+}
+
+async fn info_from_season(
+    ctx: &Context,
+    season: Media<Season>,
+    url_filter: &UrlFilter,
+    duplicated: bool,
+) -> Result<SeasonInfo> {
+    let mut episodes = vec![];
+    for episode in cache::episodes(ctx, &season).await? {
+        if url_filter.is_episode_valid(
+            episode.metadata.episode_number,
+            episode.metadata.season_number,
+        ) {
+            episodes.push(EpisodeInfo::from_episode(ctx, &episode).await?)
+        }
+    }
+
+    Ok(SeasonInfo {
+        duplicated,
+        episodes,
+        ..SeasonInfo::from(&season)
+    })
+}
+
+fn join_or_none(locales: &[String]) -> String {
+    if locales.is_empty() {
+        "none".to_string()
+    } else {
+        locales.join(", ")
+    }
+}
@@ -1,16 +1,55 @@
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use lazy_static::lazy_static;
+use log::kv::{Key, Value, VisitSource};
 use log::{
     set_boxed_logger, set_max_level, Level, LevelFilter, Log, Metadata, Record, SetLoggerError,
 };
+use serde_json::{Map, Value as JsonValue};
 use std::io::{stdout, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 use std::thread;
 use std::time::Duration;
 
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref MULTI_PROGRESS: MultiProgress = MultiProgress::new();
+}
+
+/// Return if the log output is json. Can be used to replace interactive output (like progress
+/// bars) with events.
+pub fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Progress bars which may be shown at the same time (e.g. the download progress of multiple
+/// episodes) must be added to this. Log output is printed above them.
+pub fn multi_progress() -> &'static MultiProgress {
+    &MULTI_PROGRESS
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+impl LogFormat {
+    pub(crate) fn parse(s: &str) -> Result<LogFormat, String> {
+        Ok(match s.to_lowercase().as_str() {
+            "human" => LogFormat::Human,
+            "json" => LogFormat::Json,
+            _ => return Err(format!("'{}' is not a valid log format", s)),
+        })
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub struct CliLogger {
     all: bool,
     level: LevelFilter,
+    format: LogFormat,
     progress: Mutex<Option<ProgressBar>>,
 }
 
@@ -23,11 +62,20 @@ impl Log for CliLogger {
         if !self.enabled(record.metadata())
             || (record.target() != "progress"
                 && record.target() != "progress_end"
+                && record.target() != "event"
                 && (!self.all && !record.target().starts_with("crunchy_cli")))
         {
             return;
         }
 
+        if self.format == LogFormat::Json {
+            self.json(record);
+            return;
+        } else if record.target() == "event" {
+            // events are only relevant for machine readable output
+            return;
+        }
+
         if self.level >= LevelFilter::Debug {
             self.extended(record);
             return;
@@ -54,17 +102,19 @@ impl Log for CliLogger {
 }
 
 impl CliLogger {
-    pub fn new(all: bool, level: LevelFilter) -> Self {
+    pub fn new(all: bool, level: LevelFilter, format: LogFormat) -> Self {
         Self {
             all,
             level,
+            format,
             progress: Mutex::new(None),
         }
     }
 
-    pub fn init(all: bool, level: LevelFilter) -> Result<(), SetLoggerError> {
+    pub fn init(all: bool, level: LevelFilter, format: LogFormat) -> Result<(), SetLoggerError> {
+        JSON_OUTPUT.store(format == LogFormat::Json, Ordering::Relaxed);
         set_max_level(level);
-        set_boxed_logger(Box::new(CliLogger::new(all, level)))
+        set_boxed_logger(Box::new(CliLogger::new(all, level, format)))
     }
 
     fn extended(&self, record: &Record) {
@@ -85,12 +135,45 @@ impl CliLogger {
         )
     }
 
+    /// Print the record as single line json object. The `event` field is derived from the record
+    /// target and level but can be overwritten by a `event` key-value pair. All other key-value
+    /// pairs of the record are added as fields too.
+    fn json(&self, record: &Record) {
+        let event = match record.target() {
+            "progress" => "progress_start",
+            "progress_end" => "progress_end",
+            _ => match record.level() {
+                Level::Error => "error",
+                Level::Warn => "warning",
+                _ => "info",
+            },
+        };
+
+        let mut object = Map::new();
+        object.insert(
+            "time".to_string(),
+            JsonValue::from(chrono::Utc::now().to_rfc3339()),
+        );
+        object.insert(
+            "level".to_string(),
+            JsonValue::from(record.level().as_str().to_lowercase()),
+        );
+        object.insert("event".to_string(), JsonValue::from(event));
+        object.insert(
+            "message".to_string(),
+            JsonValue::from(format!("{}", record.args()).trim()),
+        );
+        let _ = record.key_values().visit(&mut JsonVisitor(&mut object));
+
+        println!("{}", JsonValue::Object(object))
+    }
+
     fn normal(&self, record: &Record) {
-        println!(":: {}", record.args())
+        MULTI_PROGRESS.suspend(|| println!(":: {}", record.args()))
     }
 
     fn error(&self, record: &Record) {
-        eprintln!(":: {}", record.args())
+        MULTI_PROGRESS.suspend(|| eprintln!(":: {}", record.args()))
     }
 
     fn progress(&self, record: &Record, stop: bool) {
@@ -113,7 +196,9 @@ impl CliLogger {
             // we're using this (square root?) symbol instead. microsoft.
             let finish_str = "√";
 
-            let pb = ProgressBar::new_spinner();
+            // the spinner is added to the other progress bars, otherwise they would overwrite
+            // each other
+            let pb = MULTI_PROGRESS.add(ProgressBar::new_spinner());
             pb.set_style(
                 ProgressStyle::with_template(":: {spinner} {msg}")
                     .unwrap()
@@ -126,6 +211,26 @@ impl CliLogger {
     }
 }
 
+struct JsonVisitor<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let json_value = if let Some(v) = value.to_u64() {
+            JsonValue::from(v)
+        } else if let Some(v) = value.to_i64() {
+            JsonValue::from(v)
+        } else if let Some(v) = value.to_f64() {
+            JsonValue::from(v)
+        } else if let Some(v) = value.to_bool() {
+            JsonValue::from(v)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), json_value);
+        Ok(())
+    }
+}
+
 macro_rules! tab_info {
     ($($arg:tt)+) => {
         if log::max_level() == log::LevelFilter::Debug {
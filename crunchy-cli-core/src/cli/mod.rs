@@ -1,5 +1,7 @@
 pub mod archive;
+pub mod cache;
 pub mod download;
+pub mod info;
 pub mod log;
 pub mod login;
 mod utils;
@@ -1,18 +1,34 @@
+use crate::cli::log::{json_output, multi_progress};
 use crate::utils::context::Context;
+use crate::utils::ffmpeg::{ffmpeg_capabilities, ffmpeg_command};
+use crate::utils::format::Format;
+use crate::utils::mkvmerge::mkvmerge_command;
+use crate::utils::os::insufficient_space;
+use crate::utils::retry::StatusError;
 use anyhow::{bail, Result};
 use crunchyroll_rs::media::{Resolution, VariantData, VariantSegment};
 use crunchyroll_rs::{Locale, Media, Season};
-use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use futures_util::future::try_join3;
+use futures_util::{stream, StreamExt};
+use indicatif::{HumanBytes, ProgressBar, ProgressFinish, ProgressStyle};
 use lazy_static::lazy_static;
-use log::{debug, LevelFilter};
+use log::{debug, info, warn, LevelFilter};
 use regex::Regex;
+use reqwest::StatusCode;
+use serde::Deserialize;
 use std::borrow::{Borrow, BorrowMut};
 use std::collections::BTreeMap;
 use std::env;
+use std::future::Future;
 use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use std::str::FromStr;
-use std::sync::{mpsc, Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
 use tokio::task::JoinSet;
 
 pub fn find_resolution(
@@ -31,23 +47,28 @@ pub fn find_resolution(
 
 pub async fn download_segments(
     ctx: &Context,
-    writer: &mut impl Write,
+    writer: &mut (impl AsyncWrite + Unpin),
     message: Option<String>,
-    variant_data: VariantData,
+    format: &Format,
 ) -> Result<()> {
-    let segments = variant_data.segments().await?;
+    let mut variant_data = format.stream.clone();
+    let mut segments = ctx
+        .retry
+        .api("segments", || variant_data.segments())
+        .await?;
     let total_segments = segments.len();
 
     let client = Arc::new(ctx.crunchy.client());
     let count = Arc::new(Mutex::new(0));
 
-    let progress = if log::max_level() == LevelFilter::Info {
-        let estimated_file_size = (variant_data.bandwidth / 8)
-            * segments
-                .iter()
-                .map(|s| s.length.unwrap_or_default().as_secs())
-                .sum::<u64>();
+    let estimated_segment_lens: Vec<u64> = segments
+        .iter()
+        .map(|s| (variant_data.bandwidth / 8) * s.length.unwrap_or_default().as_secs())
+        .collect();
+    let mut estimated_file_size = estimated_segment_lens.iter().sum::<u64>();
+    let mut downloaded_bytes = 0;
 
+    let progress = if log::max_level() == LevelFilter::Info && !json_output() {
         let progress = ProgressBar::new(estimated_file_size)
             .with_style(
                 ProgressStyle::with_template(
@@ -56,146 +77,231 @@ pub async fn download_segments(
                 .unwrap()
                 .progress_chars("##-"),
             )
-            .with_message(message.map(|m| m + " ").unwrap_or_default())
+            .with_message(message.clone().map(|m| m + " ").unwrap_or_default())
             .with_finish(ProgressFinish::Abandon);
-        Some(progress)
+        Some(multi_progress().add(progress))
     } else {
         None
     };
 
-    let cpus = num_cpus::get();
-    let mut segs: Vec<Vec<VariantSegment>> = Vec::with_capacity(cpus);
-    for _ in 0..cpus {
-        segs.push(vec![])
-    }
-    for (i, segment) in segments.clone().into_iter().enumerate() {
-        segs[i - ((i / cpus) * cpus)].push(segment);
-    }
-
-    let (sender, receiver) = mpsc::channel();
-
-    let mut join_set: JoinSet<Result<()>> = JoinSet::new();
-    for num in 0..cpus {
-        let thread_client = client.clone();
-        let thread_sender = sender.clone();
-        let thread_segments = segs.remove(0);
-        let thread_count = count.clone();
-        join_set.spawn(async move {
-            let after_download_sender = thread_sender.clone();
-
-            // the download process is encapsulated in its own function. this is done to easily
-            // catch errors which get returned with `...?` and `bail!(...)` and that the thread
-            // itself can report that an error has occured
-            let download = || async move {
-                for (i, segment) in thread_segments.into_iter().enumerate() {
-                    let mut retry_count = 0;
-                    let mut buf = loop {
-                        let response = thread_client
-                            .get(&segment.url)
-                            .timeout(Duration::from_secs(60))
-                            .send()
+    let mut data_pos = 0;
+    let mut last_refresh_pos = None;
+    // the segment urls are signed and may expire while downloading. if this happens, the segments
+    // are fetched again and the download resumes from the first segment which is not written yet
+    let mut buf = loop {
+        // segments are not assigned to the download tasks up front. every task takes the next not
+        // yet downloaded segment when it is finished with its current one, so one slow segment does
+        // not stall all segments which would come after it
+        let round_segments = Arc::new(segments);
+        let next_segment = Arc::new(AtomicUsize::new(data_pos as usize));
+        let cancelled = Arc::new(AtomicBool::new(false));
+        // every segment which is downloaded but not yet written holds one permit of the window.
+        // this prevents the tasks from running too far ahead if one segment takes longer and keeps
+        // the memory usage of the buffer capped
+        let window = Arc::new(Semaphore::new(ctx.segment_window));
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+
+        let mut join_set: JoinSet<Result<()>> = JoinSet::new();
+        let concurrent_segments = ctx.concurrent_segments;
+        let workers = ctx
+            .concurrent_segments
+            .min(total_segments - data_pos as usize);
+        for _ in 0..workers {
+            let thread_client = client.clone();
+            let thread_rate_limiter = ctx.rate_limiter.clone();
+            let thread_retry = ctx.retry.clone();
+            let thread_sender = sender.clone();
+            let thread_segments = round_segments.clone();
+            let thread_next_segment = next_segment.clone();
+            let thread_cancelled = cancelled.clone();
+            let thread_window = window.clone();
+            let thread_slots = ctx.segment_slots.clone();
+            let thread_count = count.clone();
+            join_set.spawn(async move {
+                let after_download_sender = thread_sender.clone();
+                let after_download_cancelled = thread_cancelled.clone();
+
+                // the download process is encapsulated in its own function. this is done to easily
+                // catch errors which get returned with `...?` and `bail!(...)` and that the thread
+                // itself can report that an error has occured
+                let download = || async move {
+                    while !thread_cancelled.load(Ordering::SeqCst) {
+                        // the window gets closed if the download failed
+                        let Ok(permit) = thread_window.acquire().await else {
+                            break;
+                        };
+                        // the permit is given back when the segment is written
+                        permit.forget();
+
+                        let pos = thread_next_segment.fetch_add(1, Ordering::SeqCst);
+                        let Some(segment) = thread_segments.get(pos) else {
+                            break;
+                        };
+
+                        // if the download rate is limited, the segment may take longer than usual
+                        // as the rate is shared between all tasks of all episodes
+                        let timeout = thread_rate_limiter.as_ref().map(|rate_limiter| {
+                            let estimated_segment_size = (variant_data.bandwidth / 8)
+                                * segment.length.unwrap_or_default().as_secs();
+                            thread_retry.timeout
+                                + Duration::from_secs(
+                                    estimated_segment_size * concurrent_segments as u64
+                                        / rate_limiter.bytes_per_second(),
+                                )
+                        });
+
+                        // the slot is shared with the downloads of all other episodes
+                        let slot = thread_slots.acquire().await?;
+                        let mut buf = thread_retry
+                            .fetch(
+                                format!("segment {}", pos),
+                                || thread_client.get(&segment.url),
+                                thread_rate_limiter.as_deref(),
+                                timeout,
+                            )
                             .await?;
+                        drop(slot);
 
-                        match response.bytes().await {
-                            Ok(b) => break b.to_vec(),
-                            Err(e) => {
-                                if e.is_body() {
-                                    if retry_count == 5 {
-                                        bail!("Max retry count reached ({}), multiple errors occured while receiving segment {}: {}", retry_count, num + (i * cpus), e)
-                                    }
-                                    debug!("Failed to download segment {} ({}). Retrying, {} out of 5 retries left", num + (i * cpus), e, 5 - retry_count)
-                                } else {
-                                    bail!("{}", e)
-                                }
-                            }
-                        }
-
-                        retry_count += 1;
-                    };
+                        buf = VariantSegment::decrypt(buf.borrow_mut(), segment.key.clone())?
+                            .to_vec();
 
-                    buf = VariantSegment::decrypt(buf.borrow_mut(), segment.key)?.to_vec();
+                        let mut c = thread_count.lock().unwrap();
+                        debug!(
+                            "Downloaded and decrypted segment [{}/{} {:.2}%] {}",
+                            pos,
+                            total_segments,
+                            ((*c + 1) as f64 / total_segments as f64) * 100f64,
+                            segment.url
+                        );
 
-                    let mut c = thread_count.lock().unwrap();
-                    debug!(
-                        "Downloaded and decrypted segment [{}/{} {:.2}%] {}",
-                        num + (i * cpus),
-                        total_segments,
-                        ((*c + 1) as f64 / total_segments as f64) * 100f64,
-                        segment.url
-                    );
+                        thread_sender.send((pos as i32, buf))?;
 
-                    thread_sender.send((num as i32 + (i * cpus) as i32, buf))?;
+                        *c += 1;
+                    }
+                    Ok(())
+                };
 
-                    *c += 1;
+                let result = download().await;
+                if result.is_err() {
+                    // stop all other tasks from taking new segments
+                    after_download_cancelled.store(true, Ordering::SeqCst);
+                    after_download_sender.send((-1 as i32, vec![]))?;
                 }
-                Ok(())
-            };
-
-
-            let result = download().await;
-            if result.is_err() {
-                after_download_sender.send((-1 as i32, vec![]))?;
-            }
-
-            result
-        });
-    }
-    // drop the sender already here so it does not outlive all (download) threads which are the only
-    // real consumers of it
-    drop(sender);
 
-    // this is the main loop which writes the data. it uses a BTreeMap as a buffer as the write
-    // happens synchronized. the download consist of multiple segments. the map keys are representing
-    // the segment number and the values the corresponding bytes
-    let mut data_pos = 0;
-    let mut buf: BTreeMap<i32, Vec<u8>> = BTreeMap::new();
-    for (pos, bytes) in receiver.iter() {
-        // if the position is lower than 0, an error occured in the sending download thread
-        if pos < 0 {
-            break;
+                result
+            });
         }
+        // drop the sender already here so it does not outlive all (download) threads which are the
+        // only real consumers of it
+        drop(sender);
+
+        // this is the main loop which writes the data. it uses a BTreeMap as a buffer as the write
+        // happens synchronized. the download consist of multiple segments. the map keys are
+        // representing the segment number and the values the corresponding bytes
+        let mut buf: BTreeMap<i32, Vec<u8>> = BTreeMap::new();
+        while let Some((pos, bytes)) = receiver.recv().await {
+            // if the position is lower than 0, an error occured in the sending download thread
+            if pos < 0 {
+                // wake up all tasks which are waiting for the window
+                window.close();
+                break;
+            }
 
-        if let Some(p) = &progress {
-            let progress_len = p.length().unwrap();
-            let estimated_segment_len = (variant_data.bandwidth / 8)
-                * segments
-                    .get(pos as usize)
-                    .unwrap()
-                    .length
-                    .unwrap_or_default()
-                    .as_secs();
             let bytes_len = bytes.len() as u64;
+            estimated_file_size =
+                estimated_file_size - estimated_segment_lens[pos as usize] + bytes_len;
+            downloaded_bytes += bytes_len;
 
-            p.set_length(progress_len - estimated_segment_len + bytes_len);
-            p.inc(bytes_len)
+            if let Some(p) = &progress {
+                p.set_length(estimated_file_size);
+                p.inc(bytes_len)
+            }
+            info!(
+                target: "event",
+                event = "segment_progress",
+                episode_id = format.episode_id.as_str(),
+                audio:% = format.audio,
+                segment = pos,
+                segments = total_segments,
+                bytes = downloaded_bytes,
+                total = estimated_file_size;
+                "{}",
+                message.as_deref().unwrap_or_default()
+            );
+
+            // check if the currently sent bytes are the next in the buffer. if so, write them
+            // directly to the target without first adding them to the buffer.
+            // if not, add them to the buffer
+            if data_pos == pos {
+                writer.write_all(bytes.borrow()).await?;
+                data_pos += 1;
+                window.add_permits(1);
+            } else {
+                buf.insert(pos, bytes);
+            }
+            // check if the buffer contains the next segment(s)
+            while let Some(b) = buf.remove(&data_pos) {
+                writer.write_all(b.borrow()).await?;
+                data_pos += 1;
+                window.add_permits(1);
+            }
         }
 
-        // check if the currently sent bytes are the next in the buffer. if so, write them directly
-        // to the target without first adding them to the buffer.
-        // if not, add them to the buffer
-        if data_pos == pos {
-            writer.write_all(bytes.borrow())?;
-            data_pos += 1;
-        } else {
-            buf.insert(pos, bytes);
+        // if any error has occured while downloading it gets returned here. expired segment urls
+        // are only an error if fetching the segments again did not help
+        let mut expired = None;
+        while let Some(joined) = join_set.join_next().await {
+            match joined? {
+                Ok(()) => (),
+                Err(e) if is_expired(&e) && last_refresh_pos != Some(data_pos) => expired = Some(e),
+                Err(e) => return Err(e),
+            }
         }
-        // check if the buffer contains the next segment(s)
-        while let Some(b) = buf.remove(&data_pos) {
-            writer.write_all(b.borrow())?;
-            data_pos += 1;
+        let Some(expired) = expired else {
+            break buf;
+        };
+        debug!(
+            "Segment urls have expired ({}). Fetching them again and resuming at segment {}",
+            expired, data_pos
+        );
+
+        // segments which are downloaded but not written yet are downloaded again with the new
+        // urls
+        for (pos, bytes) in buf {
+            let bytes_len = bytes.len() as u64;
+            estimated_file_size =
+                estimated_file_size - bytes_len + estimated_segment_lens[pos as usize];
+            downloaded_bytes -= bytes_len;
+        }
+        if let Some(p) = &progress {
+            p.set_length(estimated_file_size);
+            p.set_position(downloaded_bytes)
         }
-    }
 
-    // if any error has occured while downloading it gets returned here
-    while let Some(joined) = join_set.join_next().await {
-        joined??
-    }
+        let mut refreshed = format.clone();
+        refreshed.refresh_stream(ctx).await?;
+        variant_data = refreshed.stream;
+        segments = ctx
+            .retry
+            .api("segments", || variant_data.segments())
+            .await?;
+        if segments.len() != total_segments {
+            bail!(
+                "Refreshed stream has {} instead of {} segments",
+                segments.len(),
+                total_segments
+            )
+        }
+        last_refresh_pos = Some(data_pos);
+    };
 
     // write the remaining buffer, if existent
     while let Some(b) = buf.remove(&data_pos) {
-        writer.write_all(b.borrow())?;
+        writer.write_all(b.borrow()).await?;
         data_pos += 1;
     }
+    writer.flush().await?;
 
     if !buf.is_empty() {
         bail!(
@@ -210,14 +316,377 @@ pub async fn download_segments(
     Ok(())
 }
 
+/// Run ffmpeg with the given arguments and show its progress. ffmpeg reports how much of the output
+/// it has written (`-progress`), which is shown as progress bar (with eta and encoding speed)
+/// relative to `duration`, the length of the output. For machine readable output, the progress is
+/// emitted as `ffmpeg_progress` events instead.
+pub async fn run_ffmpeg(
+    args: Vec<String>,
+    duration: Duration,
+    message: String,
+    episode_id: &str,
+) -> Result<()> {
+    let mut ffmpeg_args = vec![
+        "-progress".to_string(),
+        "pipe:1".to_string(),
+        "-nostats".to_string(),
+    ];
+    ffmpeg_args.extend(args);
+    let mut ffmpeg = tokio::process::Command::from(ffmpeg_command(&ffmpeg_args))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    ffmpeg_start_event("ffmpeg", episode_id, &message);
+    let stdout = ffmpeg.stdout.take().unwrap();
+    let mut stderr = ffmpeg.stderr.take().unwrap();
+    // stderr must be read while ffmpeg is running, otherwise ffmpeg blocks if the pipe is full
+    let stderr_task = tokio::spawn(async move {
+        let mut buf = vec![];
+        let _ = stderr.read_to_end(&mut buf).await;
+        buf
+    });
+
+    let progress = if log::max_level() == LevelFilter::Info && !json_output() {
+        let progress = ProgressBar::new(duration.as_millis() as u64)
+            .with_style(
+                ProgressStyle::with_template(
+                    ":: {msg} {prefix:>6} [{wide_bar}] {percent:>3}% (eta {eta})",
+                )
+                .unwrap()
+                .progress_chars("##-"),
+            )
+            .with_message(message.clone())
+            .with_finish(ProgressFinish::Abandon);
+        Some(multi_progress().add(progress))
+    } else {
+        None
+    };
+
+    // ffmpeg writes blocks of `key=value` lines, every block ends with a `progress` line
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    let mut out_time = Duration::ZERO;
+    let mut speed = None;
+    while let Some(line) = lines.next_line().await? {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        match key {
+            // despite the name, `out_time_ms` is in microseconds too
+            "out_time_us" | "out_time_ms" => {
+                if let Ok(us) = value.trim().parse::<u64>() {
+                    out_time = Duration::from_micros(us)
+                }
+            }
+            "speed" => speed = value.trim().trim_end_matches('x').parse::<f64>().ok(),
+            "progress" => {
+                if let Some(p) = &progress {
+                    p.set_position((out_time.as_millis() as u64).min(p.length().unwrap_or(0)));
+                    if let Some(speed) = speed {
+                        p.set_prefix(format!("{:.2}x", speed))
+                    }
+                }
+                info!(
+                    target: "event",
+                    event = "ffmpeg_progress",
+                    episode_id = episode_id,
+                    time = out_time.as_secs_f64(),
+                    duration = duration.as_secs_f64(),
+                    speed = speed.unwrap_or_default(),
+                    done = value.trim() == "end";
+                    "{}",
+                    message
+                )
+            }
+            _ => (),
+        }
+    }
+
+    let status = ffmpeg.wait().await?;
+    let stderr = stderr_task.await?;
+    ffmpeg_end_event("ffmpeg", episode_id, &message, status.success());
+    if let Some(p) = &progress {
+        if status.success() {
+            p.finish()
+        } else {
+            p.abandon()
+        }
+    }
+    if !status.success() {
+        bail!("{}", String::from_utf8_lossy(stderr.as_slice()))
+    }
+
+    Ok(())
+}
+
+/// Run mkvmerge with the given arguments and show its progress. mkvmerge reports the progress in
+/// percent (`--gui-mode`), which is shown as progress bar. For machine readable output, the
+/// progress is emitted as `mkvmerge_progress` events instead.
+pub async fn run_mkvmerge(args: Vec<String>, message: String, episode_id: &str) -> Result<()> {
+    let mut mkvmerge_args = vec!["--gui-mode".to_string()];
+    mkvmerge_args.extend(args);
+    let mut mkvmerge = tokio::process::Command::from(mkvmerge_command(&mkvmerge_args))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    ffmpeg_start_event("mkvmerge", episode_id, &message);
+    let stdout = mkvmerge.stdout.take().unwrap();
+
+    let progress = if log::max_level() == LevelFilter::Info && !json_output() {
+        let progress = ProgressBar::new(100)
+            .with_style(
+                ProgressStyle::with_template(":: {msg} [{wide_bar}] {percent:>3}% (eta {eta})")
+                    .unwrap()
+                    .progress_chars("##-"),
+            )
+            .with_message(message.clone())
+            .with_finish(ProgressFinish::Abandon);
+        Some(multi_progress().add(progress))
+    } else {
+        None
+    };
+
+    // mkvmerge writes warnings and errors to stdout too, they're needed if it fails
+    let mut output = vec![];
+    let mut lines = tokio::io::BufReader::new(stdout).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(percent) = line
+            .strip_prefix("#GUI#progress ")
+            .and_then(|p| p.trim().trim_end_matches('%').parse::<u64>().ok())
+        {
+            if let Some(p) = &progress {
+                p.set_position(percent.min(100))
+            }
+            info!(
+                target: "event",
+                event = "mkvmerge_progress",
+                episode_id = episode_id,
+                percent = percent,
+                done = percent >= 100;
+                "{}",
+                message
+            )
+        } else if let Some(warning) = line.strip_prefix("Warning: ") {
+            warn!("mkvmerge: {}", warning)
+        } else if !line.starts_with("#GUI#") {
+            output.push(line)
+        }
+    }
+
+    let status = mkvmerge.wait().await?;
+    // exit code 1 means that mkvmerge finished with warnings
+    let success = status.success() || status.code() == Some(1);
+    ffmpeg_end_event("mkvmerge", episode_id, &message, success);
+    if let Some(p) = &progress {
+        if success {
+            p.finish()
+        } else {
+            p.abandon()
+        }
+    }
+    if !success {
+        bail!("{}", output.join("\n"))
+    }
+
+    Ok(())
+}
+
+/// Run `count` jobs through two stages. `download` fetches everything a job needs and `encode`
+/// turns it into the output file. Up to [`Context::jobs`] jobs are downloaded and
+/// [`Context::encode_jobs`] are encoded at the same time, so the network is not idle while ffmpeg
+/// is running. Downloaded jobs wait in a queue for the encoder which holds at most
+/// [`Context::jobs`] jobs; if it is full, the downloads pause instead of filling up the disk with
+/// temporary files. `finished` is called for every job in the order of the jobs, not in the order
+/// they finish. If it returns an error, all running jobs are cancelled.
+pub(crate) async fn run_pipeline<T, DFut, EFut>(
+    ctx: &Context,
+    count: usize,
+    download: impl Fn(usize) -> DFut,
+    encode: impl Fn(usize, T) -> EFut,
+    mut finished: impl FnMut(usize, Duration, Result<()>) -> Result<()>,
+) -> Result<()>
+where
+    DFut: Future<Output = Result<T>>,
+    EFut: Future<Output = Result<()>>,
+{
+    let (encode_sender, mut encode_receiver) = mpsc::channel::<(usize, Instant, T)>(ctx.jobs);
+    let (result_sender, mut result_receiver) = mpsc::unbounded_channel();
+    let download_result_sender = result_sender.clone();
+
+    let download_stage = async move {
+        stream::iter(0..count)
+            .map(|i| {
+                let (encode_sender, result_sender) = (&encode_sender, &download_result_sender);
+                let download = &download;
+                async move {
+                    let start = Instant::now();
+                    match download(i).await {
+                        // waits if the queue is full. the receiver is only gone if the pipeline
+                        // is cancelled
+                        Ok(downloaded) => {
+                            let _ = encode_sender.send((i, start, downloaded)).await;
+                        }
+                        Err(e) => {
+                            let _ = result_sender.send((i, start.elapsed(), Err(e)));
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(ctx.jobs)
+            .collect::<Vec<()>>()
+            .await;
+        // closes the queue, the encode stage stops once it is empty
+        drop(encode_sender);
+        drop(download_result_sender);
+        Ok::<(), anyhow::Error>(())
+    };
+
+    let encode_stage = async move {
+        stream::poll_fn(|cx| encode_receiver.poll_recv(cx))
+            .map(|(i, start, downloaded)| {
+                let encode = &encode;
+                async move {
+                    let result = encode(i, downloaded).await;
+                    (i, start.elapsed(), result)
+                }
+            })
+            .buffer_unordered(ctx.encode_jobs)
+            .for_each(|result| {
+                let _ = result_sender.send(result);
+                async {}
+            })
+            .await;
+        Ok(())
+    };
+
+    let report_stage = async move {
+        let mut done = BTreeMap::new();
+        let mut next = 0;
+        while let Some((i, duration, result)) = result_receiver.recv().await {
+            done.insert(i, (duration, result));
+
+            while let Some((duration, result)) = done.remove(&next) {
+                finished(next, duration, result)?;
+                next += 1
+            }
+        }
+        Ok(())
+    };
+
+    try_join3(download_stage, encode_stage, report_stage).await?;
+    Ok(())
+}
+
+/// Check if a segment download failed because its (signed) url has expired.
+fn is_expired(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<StatusError>()
+        .is_some_and(|e| e.status == StatusCode::FORBIDDEN)
+}
+
+/// Emit the event that `program` (ffmpeg or mkvmerge) starts generating a file of an episode.
+pub fn ffmpeg_start_event(program: &str, episode_id: &str, message: &str) {
+    info!(
+        target: "event",
+        event = "ffmpeg_start",
+        program = program,
+        episode_id = episode_id;
+        "{}",
+        message
+    )
+}
+
+/// Emit the event that `program` (ffmpeg or mkvmerge) has exited, see [`ffmpeg_start_event`].
+pub fn ffmpeg_end_event(program: &str, episode_id: &str, message: &str, success: bool) {
+    info!(
+        target: "event",
+        event = "ffmpeg_end",
+        program = program,
+        episode_id = episode_id,
+        success = success;
+        "{}",
+        message
+    )
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum FFmpegPreset {
     Predefined(FFmpegCodec, Option<FFmpegHwAccel>, FFmpegQuality),
+    Named(NamedFFmpegPreset),
     Custom(Option<String>, Option<String>),
 }
 
+/// A preset defined by the user in the presets file (see [`presets_file`]). All arguments are
+/// shell-like strings. If `video` or `audio` is not set, the respective streams are copied.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamedFFmpegPreset {
+    description: Option<String>,
+    /// Arguments placed before the input files.
+    input: Option<String>,
+    /// Video encoding arguments, e.g. `-c:v libx265 -crf 24`.
+    video: Option<String>,
+    /// Audio encoding arguments, e.g. `-c:a libopus -b:a 128k`.
+    audio: Option<String>,
+    /// Any other arguments placed before the output file.
+    output: Option<String>,
+}
+
 lazy_static! {
     static ref PREDEFINED_PRESET: Regex = Regex::new(r"^\w+(-\w+)*?$").unwrap();
+    static ref NAMED_PRESETS: Result<BTreeMap<String, NamedFFmpegPreset>, String> =
+        load_named_presets();
+}
+
+/// Path of the file containing the user defined ffmpeg presets.
+pub(crate) fn presets_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|config_dir| config_dir.join("crunchy-cli").join("presets.json"))
+}
+
+fn load_named_presets() -> Result<BTreeMap<String, NamedFFmpegPreset>, String> {
+    let Some(path) = presets_file() else {
+        return Ok(BTreeMap::new());
+    };
+    let content = match std::fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.to_string_lossy(), e)),
+    };
+
+    parse_named_presets(&content, &path)
+}
+
+fn parse_named_presets(
+    content: &[u8],
+    path: &Path,
+) -> Result<BTreeMap<String, NamedFFmpegPreset>, String> {
+    let presets: BTreeMap<String, NamedFFmpegPreset> = serde_json::from_slice(content)
+        .map_err(|e| format!("Failed to parse {}: {}", path.to_string_lossy(), e))?;
+    for (name, preset) in &presets {
+        // a named preset would silently replace the built-in one
+        if FFmpegPreset::parse_predefined(name).is_ok() {
+            return Err(format!(
+                "ffmpeg preset '{}' has the same name as a built-in preset ({})",
+                name,
+                path.to_string_lossy()
+            ));
+        }
+        for (field, args) in [
+            ("input", &preset.input),
+            ("video", &preset.video),
+            ("audio", &preset.audio),
+            ("output", &preset.output),
+        ] {
+            if args.as_ref().is_some_and(|a| shlex::split(a).is_none()) {
+                return Err(format!(
+                    "Failed to parse {} args of ffmpeg preset '{}' ({})",
+                    field,
+                    name,
+                    path.to_string_lossy()
+                ));
+            }
+        }
+    }
+    Ok(presets)
 }
 
 macro_rules! FFmpegEnum {
@@ -239,11 +708,11 @@ macro_rules! FFmpegEnum {
             }
         }
 
-        impl ToString for $name {
-            fn to_string(&self) -> String {
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
                 match self {
                     $(
-                        &$name::$field => stringify!($field).to_string().to_lowercase()
+                        &$name::$field => write!(f, "{}", stringify!($field).to_lowercase())
                     ),*
                 }
             }
@@ -329,23 +798,16 @@ impl FFmpegPreset {
         for (codec, hwaccel, quality) in FFmpegPreset::available_matches() {
             let mut description_details = vec![];
             if let Some(h) = &hwaccel {
-                description_details.push(format!("{} hardware acceleration", h.to_string()))
+                description_details.push(format!("{} hardware acceleration", h))
             }
             if let Some(q) = &quality {
-                description_details.push(format!("{} video quality/compression", q.to_string()))
+                description_details.push(format!("{} video quality/compression", q))
             }
 
             let description = if description_details.len() == 0 {
-                format!(
-                    "{} encoded with default video quality/compression",
-                    codec.to_string()
-                )
+                format!("{} encoded with default video quality/compression", codec)
             } else if description_details.len() == 1 {
-                format!(
-                    "{} encoded with {}",
-                    codec.to_string(),
-                    description_details[0]
-                )
+                format!("{} encoded with {}", codec, description_details[0])
             } else {
                 let first = description_details.remove(0);
                 let last = description_details.remove(description_details.len() - 1);
@@ -355,13 +817,7 @@ impl FFmpegPreset {
                     "".to_string()
                 };
 
-                format!(
-                    "{} encoded with {}{} and {}",
-                    codec.to_string(),
-                    first,
-                    mid,
-                    last
-                )
+                format!("{} encoded with {}{} and {}", codec, first, mid, last)
             };
 
             return_values.push(format!(
@@ -378,14 +834,43 @@ impl FFmpegPreset {
                 description
             ))
         }
+        match NAMED_PRESETS.as_ref() {
+            Ok(named_presets) => {
+                for (name, preset) in named_presets {
+                    return_values.push(format!(
+                        "{} ({})",
+                        name,
+                        preset
+                            .description
+                            .clone()
+                            .unwrap_or("user defined preset".to_string())
+                    ))
+                }
+            }
+            Err(e) => {
+                return_values.push(format!("(user defined presets are not available: {})", e))
+            }
+        }
         return_values
     }
 
     pub(crate) fn parse(s: &str) -> Result<FFmpegPreset, String> {
+        let predefined = FFmpegPreset::parse_predefined(s);
+        // a broken presets file is only an error if `s` could be the name of a preset in it
+        let named_preset = match NAMED_PRESETS.as_ref() {
+            Ok(named_presets) => named_presets.get(s).cloned(),
+            Err(e) if predefined.is_err() && PREDEFINED_PRESET.is_match(s) => return Err(e.clone()),
+            Err(_) => None,
+        };
         let env_ffmpeg_input_args = env::var("FFMPEG_INPUT_ARGS").ok();
         let env_ffmpeg_output_args = env::var("FFMPEG_OUTPUT_ARGS").ok();
 
         if env_ffmpeg_input_args.is_some() || env_ffmpeg_output_args.is_some() {
+            // the env variables replace custom ffmpeg args. silently replacing a preset the user
+            // explicitly chose would be confusing
+            if named_preset.is_some() || predefined.is_ok() {
+                return Err(format!("'{}' is a preset but `FFMPEG_INPUT_ARGS` / `FFMPEG_OUTPUT_ARGS` are set and would override it. Unset them to use the preset", s));
+            }
             if let Some(input) = &env_ffmpeg_input_args {
                 if shlex::split(input).is_none() {
                     return Err(format!("Failed to parse custom ffmpeg input '{}' (`FFMPEG_INPUT_ARGS` env variable)", input));
@@ -401,10 +886,16 @@ impl FFmpegPreset {
                 env_ffmpeg_input_args,
                 env_ffmpeg_output_args,
             ));
+        } else if let Some(preset) = named_preset {
+            return Ok(FFmpegPreset::Named(preset));
         } else if !PREDEFINED_PRESET.is_match(s) {
             return Ok(FFmpegPreset::Custom(None, Some(s.to_string())));
         }
 
+        predefined
+    }
+
+    fn parse_predefined(s: &str) -> Result<FFmpegPreset, String> {
         let mut codec: Option<FFmpegCodec> = None;
         let mut hwaccel: Option<FFmpegHwAccel> = None;
         let mut quality: Option<FFmpegQuality> = None;
@@ -416,8 +907,7 @@ impl FFmpegPreset {
                 if let Some(cc) = codec {
                     return Err(format!(
                         "cannot use multiple codecs (found {} and {})",
-                        cc.to_string(),
-                        c.to_string()
+                        cc, c
                     ));
                 }
                 codec = Some(c)
@@ -428,8 +918,7 @@ impl FFmpegPreset {
                 if let Some(hh) = hwaccel {
                     return Err(format!(
                         "cannot use multiple hardware accelerations (found {} and {})",
-                        hh.to_string(),
-                        h.to_string()
+                        hh, h
                     ));
                 }
                 hwaccel = Some(h)
@@ -440,8 +929,7 @@ impl FFmpegPreset {
                 if let Some(qq) = quality {
                     return Err(format!(
                         "cannot use multiple ffmpeg preset qualities (found {} and {})",
-                        qq.to_string(),
-                        q.to_string()
+                        qq, q
                     ));
                 }
                 quality = Some(q)
@@ -477,6 +965,18 @@ impl FFmpegPreset {
                 input.map_or(vec![], |i| shlex::split(&i).unwrap_or_default()),
                 output.map_or(vec![], |o| shlex::split(&o).unwrap_or_default()),
             ),
+            FFmpegPreset::Named(preset) => {
+                let split = |args: Option<String>, default: &[&str]| {
+                    args.map_or(default.iter().map(|s| s.to_string()).collect(), |a| {
+                        shlex::split(&a).unwrap_or_default()
+                    })
+                };
+
+                let mut output = split(preset.video, &["-c:v", "copy"]);
+                output.extend(split(preset.audio, &["-c:a", "copy"]));
+                output.extend(split(preset.output, &[]));
+                (split(preset.input, &[]), output)
+            }
             FFmpegPreset::Predefined(codec, hwaccel_opt, quality) => {
                 let mut input = vec![];
                 let mut output = vec![];
@@ -544,6 +1044,135 @@ impl FFmpegPreset {
     }
 }
 
+/// The ffmpeg input and output args of the given presets. Without any preset, video and audio are
+/// copied.
+pub(crate) fn preset_args(
+    ffmpeg_preset: Option<&FFmpegPreset>,
+    audio_preset: Option<&AudioPreset>,
+) -> (Vec<String>, Vec<String>) {
+    let (input, output) = if let Some(preset) = ffmpeg_preset.cloned() {
+        preset.to_input_output_args()
+    } else {
+        (
+            vec![],
+            vec![
+                "-c:v".to_string(),
+                "copy".to_string(),
+                "-c:a".to_string(),
+                "copy".to_string(),
+            ],
+        )
+    };
+    if let Some(audio_preset) = audio_preset {
+        (input, audio_preset.apply(output))
+    } else {
+        (input, output)
+    }
+}
+
+/// Check if ffmpeg is installed and supports everything the given presets need.
+pub(crate) fn check_ffmpeg(
+    ffmpeg_preset: Option<&FFmpegPreset>,
+    audio_preset: Option<&AudioPreset>,
+) -> Result<()> {
+    let Some(ffmpeg) = ffmpeg_capabilities() else {
+        bail!("FFmpeg is needed to run this command")
+    };
+    let (input, output) = preset_args(ffmpeg_preset, audio_preset);
+    ffmpeg.validate(&input, &output)
+}
+
+FFmpegEnum! {
+    enum AudioCodec {
+        Opus,
+        Aac,
+        Flac
+    }
+}
+
+/// Codec and (optional) bitrate every audio track is transcoded to. Independent of
+/// [`FFmpegPreset`], which only changes the video and copies the audio.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AudioPreset {
+    codec: AudioCodec,
+    /// Bitrate in kbit/s.
+    bitrate: Option<u32>,
+}
+
+impl AudioPreset {
+    pub(crate) fn available_matches_human_readable() -> Vec<String> {
+        vec![
+            "opus (opus encoded, e.g. 'opus-128k' to set the bitrate)".to_string(),
+            "aac (aac encoded, e.g. 'aac-192k' to set the bitrate)".to_string(),
+            "flac (lossless flac encoded)".to_string(),
+        ]
+    }
+
+    pub(crate) fn parse(s: &str) -> Result<AudioPreset, String> {
+        let (codec, bitrate) = s.split_once('-').map_or((s, None), |(c, b)| (c, Some(b)));
+
+        let Some(codec) = AudioCodec::all()
+            .into_iter()
+            .find(|c| c.to_string() == codec.to_lowercase())
+        else {
+            return Err(format!("'{}' is not a valid audio codec", codec));
+        };
+        let bitrate = match bitrate {
+            Some(_) if codec == AudioCodec::Flac => {
+                return Err("flac is lossless and has no bitrate".to_string())
+            }
+            Some(b) => match b.to_lowercase().trim_end_matches('k').parse::<u32>() {
+                Ok(bitrate) if bitrate > 0 => Some(bitrate),
+                _ => return Err(format!("'{}' is not a valid bitrate (e.g. '128k')", b)),
+            },
+            None => None,
+        };
+
+        Ok(AudioPreset { codec, bitrate })
+    }
+
+    /// Replace the audio codec and bitrate arguments in `output_args` (from a [`FFmpegPreset`] or
+    /// the default `-c:a copy`) with the ones of this preset. The arguments apply to every audio
+    /// track of the output.
+    pub(crate) fn apply(&self, output_args: Vec<String>) -> Vec<String> {
+        let mut args = vec![];
+        let mut iter = output_args.into_iter();
+        while let Some(arg) = iter.next() {
+            if is_audio_codec_arg(&arg) {
+                // skip the value too
+                iter.next();
+            } else {
+                args.push(arg)
+            }
+        }
+
+        args.extend(["-c:a".to_string(), self.encoder().to_string()]);
+        if let Some(bitrate) = self.bitrate {
+            args.extend(["-b:a".to_string(), format!("{}k", bitrate)])
+        }
+        args
+    }
+
+    fn encoder(&self) -> &str {
+        match self.codec {
+            AudioCodec::Opus => "libopus",
+            AudioCodec::Aac => "aac",
+            AudioCodec::Flac => "flac",
+        }
+    }
+}
+
+/// If `arg` sets the codec or bitrate of audio streams. Options with a stream specifier like
+/// `-c:a:0` are more specific than `-c:a` and would take precedence over it.
+fn is_audio_codec_arg(arg: &str) -> bool {
+    arg == "-acodec"
+        || arg == "-ab"
+        || ["-c:a", "-codec:a", "-b:a"].iter().any(|option| {
+            arg.strip_prefix(option)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(':'))
+        })
+}
+
 lazy_static! {
     static ref DUPLICATED_SEASONS_MULTILANG_REGEX: Regex = Regex::new(r"(-arabic|-castilian|-english|-english-in|-french|-german|-hindi|-italian|-portuguese|-russian|-spanish)$").unwrap();
 }
@@ -596,6 +1225,59 @@ pub(crate) fn all_locale_in_locales(locales: Vec<Locale>) -> Vec<Locale> {
     }
 }
 
+/// Log the estimated size of a job and check if enough disk space is available for it. If not,
+/// the user is asked if the job should be started anyway. With `yes` (no interactive input) the job
+/// is refused. A dry run only prints the warnings.
+pub(crate) fn check_disk_space(
+    temp_size: u64,
+    outputs: Vec<(PathBuf, u64)>,
+    yes: bool,
+    dry_run: bool,
+) -> Result<()> {
+    if outputs.is_empty() && temp_size == 0 {
+        return Ok(());
+    }
+
+    info!(
+        "Estimated size: {} in {} file(s), up to {} of temporary files",
+        HumanBytes(outputs.iter().map(|(_, size)| size).sum()),
+        outputs.len(),
+        HumanBytes(temp_size)
+    );
+
+    let messages = insufficient_space(temp_size, outputs)?;
+    if messages.is_empty() {
+        return Ok(());
+    }
+
+    for message in &messages {
+        warn!("Not enough disk space: {}", message)
+    }
+    if dry_run {
+        Ok(())
+    } else if yes {
+        bail!("Not enough disk space available")
+    } else if interactive_confirm("Not enough disk space available. Continue anyway?") {
+        Ok(())
+    } else {
+        bail!("Aborted because of missing disk space")
+    }
+}
+
+/// Ask the user a yes / no question. Everything except 'y' / 'yes' is treated as no.
+pub(crate) fn interactive_confirm<S: AsRef<str>>(question: S) -> bool {
+    let mut stdout = std::io::stdout();
+    let _ = write!(stdout, ":: {} [y/N] ", question.as_ref());
+    let _ = stdout.flush();
+    let mut user_input = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut user_input)
+        .expect("cannot open stdin");
+
+    matches!(user_input.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
 pub(crate) fn interactive_season_choosing(seasons: Vec<Media<Season>>) -> Vec<Media<Season>> {
     let input_regex =
         Regex::new(r"((?P<single>\d+)|(?P<range_from>\d+)-(?P<range_to>\d+)?)(\s|$)").unwrap();
@@ -691,3 +1373,55 @@ pub(crate) fn interactive_season_choosing(seasons: Vec<Media<Season>>) -> Vec<Me
         .flatten()
         .collect::<Vec<Media<Season>>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_presets() {
+        let path = Path::new("presets.json");
+        let presets = parse_named_presets(
+            br#"{"anime": {"description": "small anime", "video": "-c:v libx265 -crf 26"}}"#,
+            path,
+        )
+        .unwrap();
+        assert_eq!(presets["anime"].description.as_deref(), Some("small anime"));
+
+        for name in ["h264", "h265-normal", "lossless-av1", "nvidia-h264-low"] {
+            let err = parse_named_presets(
+                format!(r#"{{"{}": {{"video": "-c:v libx264"}}}}"#, name).as_bytes(),
+                path,
+            )
+            .unwrap_err();
+            assert!(err.contains("same name as a built-in preset"), "{}", err)
+        }
+
+        assert!(parse_named_presets(br#"{"anime": {"video": "'-c:v"}}"#, path).is_err());
+        assert!(parse_named_presets(br#"{"anime": {"crf": 26}}"#, path).is_err());
+    }
+
+    #[test]
+    fn audio_preset_apply() {
+        let args = |args: &str| shlex::split(args).unwrap();
+        let preset = AudioPreset::parse("opus-96k").unwrap();
+
+        assert_eq!(
+            preset.apply(args("-c:v libx265 -c:a copy")),
+            args("-c:v libx265 -c:a libopus -b:a 96k")
+        );
+        assert_eq!(
+            preset.apply(args(
+                "-c:v libx265 -c:a:0 aac -b:a:1 128k -codec:a:0 ac3 -acodec mp3 -ab 64k -crf 24"
+            )),
+            args("-c:v libx265 -crf 24 -c:a libopus -b:a 96k")
+        );
+        // other options which start the same are kept
+        assert_eq!(
+            AudioPreset::parse("flac")
+                .unwrap()
+                .apply(args("-c:attachment copy -b:v 2M")),
+            args("-c:attachment copy -b:v 2M -c:a flac")
+        );
+    }
+}
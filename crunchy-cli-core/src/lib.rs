@@ -1,18 +1,27 @@
-use crate::cli::log::CliLogger;
+use crate::cli::log::{CliLogger, LogFormat};
+use crate::utils::cache::{CacheMode, MetadataCache};
 use crate::utils::context::Context;
+use crate::utils::ffmpeg::init_ffmpeg;
 use crate::utils::locale::system_locale;
 use crate::utils::log::progress;
+use crate::utils::mkvmerge::init_mkvmerge;
+use crate::utils::rate_limit::RateLimiter;
+use crate::utils::retry::RetryPolicy;
 use anyhow::bail;
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use crunchyroll_rs::{Crunchyroll, Locale};
 use log::{debug, error, warn, LevelFilter};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{env, fs};
+use tokio::sync::Semaphore;
 
 mod cli;
 mod utils;
 
-pub use cli::{archive::Archive, download::Download, login::Login};
+pub use cli::{archive::Archive, cache::Cache, download::Download, info::Info, login::Login};
 
 #[async_trait::async_trait(?Send)]
 trait Execute {
@@ -26,20 +35,155 @@ trait Execute {
 #[clap(author, version = version(), about)]
 #[clap(name = "crunchy-cli")]
 pub struct Cli {
+    #[clap(flatten)]
+    options: GlobalOptions,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+// options which are valid for every command. not a doc comment, clap would use it as about text
+#[derive(Debug, clap::Args)]
+struct GlobalOptions {
     #[clap(flatten)]
     verbosity: Option<Verbosity>,
 
+    #[arg(help = "Format of the log output. Valid formats are 'human' and 'json'")]
+    #[arg(
+        long_help = "Format of the log output. Valid formats are 'human' and 'json'. \
+    'json' prints one json object per line for every event (e.g. parsed urls, selected formats, segment download progress, ffmpeg stages, finished files, warnings and errors) \
+    instead of human readable text and progress bars"
+    )]
+    #[arg(long, default_value = "human")]
+    #[arg(value_parser = LogFormat::parse)]
+    log_format: LogFormat,
+
     #[arg(
         help = "Overwrite the language in which results are returned. Default is your system language"
     )]
     #[arg(long)]
     lang: Option<Locale>,
 
+    #[arg(help = "Number of episodes which are downloaded at the same time")]
+    #[arg(
+        long_help = "Number of episodes which are downloaded at the same time. \
+    All episodes share the segments which can be downloaded at the same time (`--concurrent-segments`). \
+    The output of every episode is printed in the order the episodes are queued"
+    )]
+    #[arg(long, default_value_t = 1)]
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    jobs: usize,
+
+    #[arg(help = "Number of episodes which are converted with ffmpeg at the same time")]
+    #[arg(
+        long_help = "Number of episodes which are converted with ffmpeg at the same time. \
+    Downloading and converting are separate steps: while an episode is converted, the next ones are already downloaded. \
+    At most as many downloaded episodes as `--jobs` wait for their conversion, the downloads pause if more are finished"
+    )]
+    #[arg(long, default_value_t = 1)]
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    encode_jobs: usize,
+
+    #[arg(help = "Number of video segments which are downloaded at the same time")]
+    #[arg(
+        long_help = "Number of video segments which are downloaded at the same time. \
+    Every download task takes the next segment as soon as it has finished its current one. \
+    Default is the number of cpu cores"
+    )]
+    #[arg(long, default_value_t = num_cpus::get())]
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    concurrent_segments: usize,
+
+    #[arg(help = "Number of api requests which are made at the same time")]
+    #[arg(
+        long_help = "Number of api requests which are made at the same time while fetching the details of series, seasons and movie listings. \
+    The order of the episodes does not depend on it"
+    )]
+    #[arg(long, default_value_t = 4)]
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    concurrent_requests: usize,
+
+    #[arg(
+        help = "Maximum number of segments which are downloaded ahead of the next one to be written"
+    )]
+    #[arg(
+        long_help = "Maximum number of segments which are downloaded ahead of the next one to be written. \
+    If one segment takes longer to download, all other segments are kept in memory until it is finished. \
+    This limits how many segments this can be and thus how much memory is used. \
+    Default is four times the number of concurrent segments"
+    )]
+    #[arg(long)]
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    segment_window: Option<usize>,
+
+    #[arg(help = "Limit the download rate, e.g. '500K' or '5M' (bytes per second)")]
+    #[arg(
+        long_help = "Limit the download rate, e.g. '500K' or '5M' (bytes per second). \
+    The limit applies to all video segments and subtitles which are downloaded at the same time together"
+    )]
+    #[arg(long)]
+    #[arg(value_parser = crate::utils::clap::clap_parse_rate)]
+    limit_rate: Option<u64>,
+
+    #[arg(help = "How often a failed request is retried")]
+    #[arg(long_help = "How often a failed request is retried. \
+    Applies to video segments, subtitles and Crunchyroll api calls which failed because of a connection error, a timeout, a server error or too many requests. \
+    The delay between two attempts grows exponentially, a delay requested by the server is respected")]
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    #[arg(help = "Timeout of a single request in seconds")]
+    #[arg(long_help = "Timeout of a single request in seconds. \
+    If the download rate is limited via `--limit-rate`, video segments get additional time depending on their size")]
+    #[arg(long, default_value_t = 60)]
+    #[arg(value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: u64,
+
+    #[arg(help = "Do not use the cache of series, season and episode metadata")]
+    #[arg(
+        long_help = "Do not use the cache of series, season and episode metadata. \
+    Nothing is read from or written to the cache"
+    )]
+    #[arg(long, default_value_t = false)]
+    no_cache: bool,
+
+    #[arg(help = "Fetch all series, season and episode metadata again and update the cache")]
+    #[arg(long, default_value_t = false)]
+    #[arg(conflicts_with = "no_cache")]
+    refresh_cache: bool,
+
+    #[arg(help = "Time in minutes after which cached metadata is fetched again")]
+    #[arg(
+        long_help = "Time in minutes after which cached metadata is fetched again. \
+    Series, season and episode metadata is cached to not request it on every run. \
+    Stream urls are never cached since they expire"
+    )]
+    #[arg(long, default_value_t = 60)]
+    cache_ttl: u64,
+
+    #[arg(help = "Path of the ffmpeg binary. Default is the ffmpeg in your PATH")]
+    #[arg(
+        long_help = "Path of the ffmpeg binary. Default is the ffmpeg in your PATH. \
+    If a ffprobe binary is next to it, it is used too"
+    )]
+    #[arg(long, env = "FFMPEG_PATH")]
+    ffmpeg_path: Option<PathBuf>,
+
+    #[arg(help = "Arguments every ffmpeg call starts with, e.g. '-threads 4'")]
+    #[arg(
+        long_help = "Arguments every ffmpeg call starts with, e.g. '-threads 4' or '-loglevel warning'. \
+    They are placed before any other argument, so only global and input options are possible"
+    )]
+    #[arg(long, env = "FFMPEG_GLOBAL_ARGS", allow_hyphen_values = true)]
+    #[arg(value_parser = crate::utils::clap::clap_parse_shell_args)]
+    ffmpeg_args: Option<crate::utils::clap::ShellArgs>,
+
+    #[arg(help = "Path of the mkvmerge binary. Default is the mkvmerge in your PATH")]
+    #[arg(long, env = "MKVMERGE_PATH")]
+    mkvmerge_path: Option<PathBuf>,
+
     #[clap(flatten)]
     login_method: LoginMethod,
-
-    #[clap(subcommand)]
-    command: Command,
 }
 
 fn version() -> String {
@@ -57,7 +201,9 @@ fn version() -> String {
 #[derive(Debug, Subcommand)]
 enum Command {
     Archive(Archive),
+    Cache(Cache),
     Download(Download),
+    Info(Info),
     Login(Login),
 }
 
@@ -98,26 +244,59 @@ struct LoginMethod {
 }
 
 pub async fn cli_entrypoint() {
-    let cli: Cli = Cli::parse();
+    let Cli {
+        options: cli,
+        command,
+    } = Cli::parse();
 
     if let Some(verbosity) = &cli.verbosity {
         if verbosity.v as u8 + verbosity.q as u8 + verbosity.vv as u8 > 1 {
             eprintln!("Output cannot be verbose ('-v') and quiet ('-q') at the same time");
             std::process::exit(1)
         } else if verbosity.v {
-            CliLogger::init(false, LevelFilter::Debug).unwrap()
+            CliLogger::init(false, LevelFilter::Debug, cli.log_format.clone()).unwrap()
         } else if verbosity.q {
-            CliLogger::init(false, LevelFilter::Error).unwrap()
+            CliLogger::init(false, LevelFilter::Error, cli.log_format.clone()).unwrap()
         } else if verbosity.vv {
-            CliLogger::init(true, LevelFilter::Debug).unwrap()
+            CliLogger::init(true, LevelFilter::Debug, cli.log_format.clone()).unwrap()
         }
     } else {
-        CliLogger::init(false, LevelFilter::Info).unwrap()
+        CliLogger::init(false, LevelFilter::Info, cli.log_format.clone()).unwrap()
     }
 
-    debug!("cli input: {:?}", cli);
+    debug!("cli input: {:?} {:?}", cli, command);
 
-    let ctx = match create_ctx(&cli).await {
+    init_ffmpeg(
+        cli.ffmpeg_path.clone(),
+        cli.ffmpeg_args.clone().map_or(vec![], |a| a.0),
+    );
+    init_mkvmerge(cli.mkvmerge_path.clone());
+
+    match command {
+        // managing the cache needs neither a session nor a context
+        Command::Cache(cache) => {
+            if let Err(err) = cache.run() {
+                error!("{}", err);
+                std::process::exit(1)
+            }
+        }
+        Command::Archive(archive) => execute_executor(archive, session_ctx(&cli).await).await,
+        Command::Download(download) => execute_executor(download, session_ctx(&cli).await).await,
+        Command::Info(info) => execute_executor(info, session_ctx(&cli).await).await,
+        Command::Login(login) => {
+            let ctx = session_ctx(&cli).await;
+            if login.remove {
+                return;
+            } else {
+                execute_executor(login, ctx).await
+            }
+        }
+    };
+}
+
+/// Log in and create the context of the commands which need it. Exits if this fails.
+async fn session_ctx(cli: &GlobalOptions) -> Context {
+    let ctx = match create_ctx(cli).await {
         Ok(ctx) => ctx,
         Err(e) => {
             error!("{}", e);
@@ -156,17 +335,7 @@ pub async fn cli_entrypoint() {
     .unwrap();
     debug!("Created ctrl-c handler");
 
-    match cli.command {
-        Command::Archive(archive) => execute_executor(archive, ctx).await,
-        Command::Download(download) => execute_executor(download, ctx).await,
-        Command::Login(login) => {
-            if login.remove {
-                return;
-            } else {
-                execute_executor(login, ctx).await
-            }
-        }
-    };
+    ctx
 }
 
 /// Cannot be done in the main function. I wanted to return `dyn` [`Execute`] from the match but had to
@@ -183,12 +352,32 @@ async fn execute_executor(mut executor: impl Execute, ctx: Context) {
     }
 }
 
-async fn create_ctx(cli: &Cli) -> Result<Context> {
-    let crunchy = crunchyroll_session(cli).await?;
-    Ok(Context { crunchy })
+async fn create_ctx(cli: &GlobalOptions) -> Result<Context> {
+    let locale = session_locale(cli)?;
+    let crunchy = crunchyroll_session(cli, locale.clone()).await?;
+    let cache_mode = if cli.no_cache {
+        CacheMode::Disabled
+    } else if cli.refresh_cache {
+        CacheMode::Refresh
+    } else {
+        CacheMode::Enabled
+    };
+    Ok(Context {
+        crunchy,
+        jobs: cli.jobs,
+        encode_jobs: cli.encode_jobs,
+        concurrent_segments: cli.concurrent_segments,
+        concurrent_requests: cli.concurrent_requests,
+        segment_slots: Arc::new(Semaphore::new(cli.concurrent_segments)),
+        segment_window: cli.segment_window.unwrap_or(cli.concurrent_segments * 4),
+        rate_limiter: cli.limit_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+        retry: RetryPolicy::new(cli.retries, Duration::from_secs(cli.timeout)),
+        cache: MetadataCache::new(cache_mode, Duration::from_secs(cli.cache_ttl * 60), &locale),
+    })
 }
 
-async fn crunchyroll_session(cli: &Cli) -> Result<Crunchyroll> {
+/// The language results are returned in.
+fn session_locale(cli: &GlobalOptions) -> Result<Locale> {
     let supported_langs = vec![
         Locale::ar_ME,
         Locale::de_DE,
@@ -222,6 +411,10 @@ async fn crunchyroll_session(cli: &Cli) -> Result<Crunchyroll> {
         lang
     };
 
+    Ok(locale)
+}
+
+async fn crunchyroll_session(cli: &GlobalOptions, locale: Locale) -> Result<Crunchyroll> {
     let builder = Crunchyroll::builder()
         .locale(locale)
         .stabilization_locales(true);
@@ -230,7 +423,7 @@ async fn crunchyroll_session(cli: &Cli) -> Result<Crunchyroll> {
         + cli.login_method.etp_rt.is_some() as u8
         + cli.login_method.anonymous as u8;
 
-    let progress_handler = progress!("Logging in");
+    let progress_handler = progress!(stage = "login"; "Logging in");
     if login_methods_count == 0 {
         if let Some(login_file_path) = cli::login::login_file_path() {
             if login_file_path.exists() {
@@ -0,0 +1,135 @@
+use anyhow::{bail, Result};
+use std::io::Read;
+
+/// Read a batch file and return the arguments of every line in it, together with the line number.
+/// If `path` is `-`, the batch is read from stdin. Stdin cannot be used for interactive input (e.g.
+/// season choosing or confirmations) then, so this is only possible with `yes` (`--yes`).
+///
+/// Every line consists of a url and optional command arguments, e.g.
+/// `https://www.crunchyroll.com/series/12345678/example[S2] --audio de-DE -o "{title}.mkv"`. Empty
+/// lines and lines starting with `#` are ignored.
+pub fn read_batch_file(path: &str, yes: bool) -> Result<Vec<(usize, Vec<String>)>> {
+    let content = if path == "-" {
+        if !yes {
+            bail!("'--batch-file -' reads the batch from stdin, which then cannot be used for interactive input. Use it together with '--yes'")
+        }
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content)?;
+        content
+    } else {
+        std::fs::read_to_string(path)?
+    };
+
+    parse_batch(&content, path)
+}
+
+fn parse_batch(content: &str, path: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut lines = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some(args) = shlex::split(line) else {
+            bail!("Failed to parse line {} of batch file '{}'", i + 1, path)
+        };
+        if !args.is_empty() {
+            lines.push((i + 1, args))
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Copy every argument of `$from` to `$to` which was not explicitly specified on the command line /
+/// batch file line that `$matches` represents. Used to apply the command line arguments as base for
+/// every line in a batch file.
+macro_rules! inherit_unset_args {
+    ($from:expr, $to:expr, $matches:expr, [$($field:ident),*]) => {
+        $(
+            if $matches.value_source(stringify!($field))
+                != Some(clap::parser::ValueSource::CommandLine)
+            {
+                $to.$field = $from.$field.clone()
+            }
+        )*
+    };
+}
+pub(crate) use inherit_unset_args;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_batch_lines() {
+        let content = "\
+# comment
+https://www.crunchyroll.com/series/GY8VEQ95Y/darling-in-the-franxx[S2] -a de-DE
+
+   \t
+  https://www.crunchyroll.com/watch/GRDQPM1ZY -o \"{title} - {audio}.mkv\" --skip-existing
+    # indented comment
+";
+        assert_eq!(
+            parse_batch(content, "test").unwrap(),
+            vec![
+                (
+                    2,
+                    vec![
+                        "https://www.crunchyroll.com/series/GY8VEQ95Y/darling-in-the-franxx[S2]"
+                            .to_string(),
+                        "-a".to_string(),
+                        "de-DE".to_string()
+                    ]
+                ),
+                (
+                    5,
+                    vec![
+                        "https://www.crunchyroll.com/watch/GRDQPM1ZY".to_string(),
+                        "-o".to_string(),
+                        "{title} - {audio}.mkv".to_string(),
+                        "--skip-existing".to_string()
+                    ]
+                )
+            ]
+        )
+    }
+
+    #[test]
+    fn parse_batch_single_quotes() {
+        assert_eq!(
+            parse_batch("url -o 'a \"b\".mkv'", "test").unwrap(),
+            vec![(
+                1,
+                vec![
+                    "url".to_string(),
+                    "-o".to_string(),
+                    "a \"b\".mkv".to_string()
+                ]
+            )]
+        )
+    }
+
+    #[test]
+    fn parse_batch_unclosed_quote() {
+        let err = parse_batch("url\nurl -o \"unclosed", "batch.txt").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Failed to parse line 2 of batch file 'batch.txt'"
+        )
+    }
+
+    #[test]
+    fn parse_batch_empty() {
+        assert!(parse_batch("\n# only comments\n\n", "test")
+            .unwrap()
+            .is_empty())
+    }
+
+    #[test]
+    fn stdin_needs_yes() {
+        assert!(read_batch_file("-", false).is_err())
+    }
+}
@@ -0,0 +1,536 @@
+use crate::utils::context::Context;
+use anyhow::Result;
+use crunchyroll_rs::media::{Video, VideoStream};
+use crunchyroll_rs::{Episode, Locale, Media, MediaCollection, Season, Series};
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How the metadata cache is used.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheMode {
+    /// Cached metadata is used if it's not expired, everything else is fetched and cached.
+    Enabled,
+    /// Cached metadata is ignored, but everything which is fetched is cached again.
+    Refresh,
+    /// The cache is neither read nor written.
+    Disabled,
+}
+
+/// On-disk cache of series, season and episode metadata. Every entry is a json file containing the
+/// time it was written and the (Crunchyroll api shaped) metadata, which is deserialized into the
+/// same types the api returns. Stream urls are signed and expire, so streams are never cached.
+pub struct MetadataCache {
+    mode: CacheMode,
+    ttl: Duration,
+    /// Metadata (e.g. titles) depends on the language results are returned in, so every language
+    /// has its own directory.
+    dir: Option<PathBuf>,
+}
+
+impl MetadataCache {
+    pub fn new(mode: CacheMode, ttl: Duration, locale: &Locale) -> Self {
+        Self {
+            mode,
+            ttl,
+            dir: cache_dir().map(|dir| dir.join(locale.to_string())),
+        }
+    }
+
+    fn read<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        if self.mode != CacheMode::Enabled {
+            return None;
+        }
+        let path = self.dir.as_ref()?.join(format!("{}.json", key));
+        let content = fs::read(&path).ok()?;
+
+        let entry: Value = match serde_json::from_slice(&content) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Ignoring corrupt cache entry {}: {}", key, e);
+                return None;
+            }
+        };
+        let created = UNIX_EPOCH + Duration::from_secs(entry["created"].as_u64()?);
+        if SystemTime::now()
+            .duration_since(created)
+            .unwrap_or_default()
+            > self.ttl
+        {
+            debug!("Cache entry {} is expired", key);
+            return None;
+        }
+
+        match serde_json::from_value(entry["value"].clone()) {
+            Ok(value) => {
+                debug!("Using cached {}", key);
+                Some(value)
+            }
+            Err(e) => {
+                debug!("Ignoring corrupt cache entry {}: {}", key, e);
+                None
+            }
+        }
+    }
+
+    fn write(&self, key: &str, value: Value) {
+        if self.mode == CacheMode::Disabled {
+            return;
+        }
+        let Some(dir) = &self.dir else { return };
+
+        let entry = json!({
+            "created": SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            "value": value,
+        });
+        // the entry is written to a temporary file first, so that concurrent runs never read a
+        // partially written entry
+        let result = fs::create_dir_all(dir)
+            .and_then(|_| tempfile::NamedTempFile::new_in(dir))
+            .and_then(|mut file| {
+                file.write_all(entry.to_string().as_bytes())?;
+                file.persist(dir.join(format!("{}.json", key)))
+                    .map(|_| ())
+                    .map_err(|e| e.error)
+            });
+        if let Err(e) = result {
+            debug!("Failed to write cache entry {}: {}", key, e)
+        }
+    }
+}
+
+/// Directory where the metadata cache is stored.
+pub fn cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|cache_dir| cache_dir.join("crunchy-cli"))
+}
+
+/// Get the series, season, episode, movie listing or movie with the given id. Only series, seasons
+/// and episodes are cached.
+pub async fn media_collection(ctx: &Context, id: &str) -> Result<MediaCollection> {
+    let key = format!("media_{}", id);
+    if let Some(media_collection) = ctx.cache.read(&key) {
+        return Ok(media_collection);
+    }
+
+    let media_collection = ctx
+        .retry
+        .api("media", || ctx.crunchy.media_collection_from_id(id))
+        .await?;
+    let value = match &media_collection {
+        MediaCollection::Series(series) => Some(series_to_json(series)),
+        MediaCollection::Season(season) => Some(season_to_json(season)),
+        MediaCollection::Episode(episode) => Some(episode_to_json(episode)),
+        _ => None,
+    };
+    if let Some(value) = value {
+        ctx.cache.write(&key, value)
+    }
+
+    Ok(media_collection)
+}
+
+/// Get all seasons of a series.
+pub async fn seasons(ctx: &Context, series: &Media<Series>) -> Result<Vec<Media<Season>>> {
+    let key = format!("seasons_{}", series.id);
+    if let Some(seasons) = ctx.cache.read(&key) {
+        return Ok(seasons);
+    }
+
+    let seasons = ctx
+        .retry
+        .api("seasons", || {
+            Media::<Season>::from_series_id(&ctx.crunchy, series.id.clone())
+        })
+        .await?;
+    ctx.cache
+        .write(&key, seasons.iter().map(season_to_json).collect());
+
+    Ok(seasons)
+}
+
+/// Get all episodes of a season.
+pub async fn episodes(ctx: &Context, season: &Media<Season>) -> Result<Vec<Media<Episode>>> {
+    let key = format!("episodes_{}", season.id);
+    if let Some(episodes) = ctx.cache.read(&key) {
+        return Ok(episodes);
+    }
+
+    let episodes = ctx
+        .retry
+        .api("episodes", || {
+            Media::<Episode>::from_season_id(&ctx.crunchy, season.id.clone())
+        })
+        .await?;
+    ctx.cache
+        .write(&key, episodes.iter().map(episode_to_json).collect());
+
+    Ok(episodes)
+}
+
+/// Get the season an episode belongs to.
+pub async fn season(ctx: &Context, episode: &Media<Episode>) -> Result<Media<Season>> {
+    let key = format!("media_{}", episode.metadata.season_id);
+    if let Some(season) = ctx.cache.read(&key) {
+        return Ok(season);
+    }
+
+    let season: Media<Season> = ctx
+        .retry
+        .api("season", || {
+            ctx.crunchy.media_from_id(&episode.metadata.season_id)
+        })
+        .await?;
+    ctx.cache.write(&key, season_to_json(&season));
+
+    Ok(season)
+}
+
+/// Get the streams of an episode. They're never cached because their urls expire. The request is
+/// made with the session of `ctx` instead of the one of the episode, which cached episodes don't
+/// have.
+pub async fn episode_streams(ctx: &Context, episode: &Media<Episode>) -> Result<VideoStream> {
+    ctx.retry
+        .api("streams", || {
+            VideoStream::from_id(
+                &ctx.crunchy,
+                episode.stream_id.clone().unwrap_or(episode.id.clone()),
+            )
+        })
+        .await
+}
+
+/// Convert a media to the json representation the Crunchyroll api uses, so that it can be
+/// deserialized again. `metadata_key` is the key the api stores the type specific metadata under.
+fn media_to_json<M: Video>(media: &Media<M>, metadata_key: &str, metadata: Value) -> Value {
+    let mut value = json!({
+        "id": media.id,
+        "external_id": media.external_id,
+        "channel_id": media.channel_id,
+        "slug": media.slug,
+        "title": media.title,
+        "slug_title": media.slug_title,
+        "promo_title": media.promo_title,
+        "description": media.description,
+        "promo_description": media.promo_description,
+    });
+    value[metadata_key] = metadata;
+    if let Some(stream_id) = &media.stream_id {
+        value["__links__"] = json!({
+            "streams": { "href": format!("/videos/{}/streams", stream_id) }
+        })
+    }
+    value
+}
+
+fn series_to_json(series: &Media<Series>) -> Value {
+    let metadata = &series.metadata;
+    media_to_json(
+        series,
+        "series_metadata",
+        json!({
+            "extended_description": metadata.extended_description,
+            "series_launch_year": metadata.series_launch_year,
+            "episode_count": metadata.episode_count,
+            "season_count": metadata.season_count,
+            "is_subbed": metadata.is_subbed,
+            "is_dubbed": metadata.is_dubbed,
+            "is_simulcast": metadata.is_simulcast,
+            "audio_locales": metadata.audio_locales,
+            "subtitle_locales": metadata.subtitle_locales,
+            "maturity_ratings": metadata.maturity_ratings,
+            "is_mature": metadata.is_mature,
+            "mature_blocked": metadata.mature_blocked,
+            "availability_notes": metadata.availability_notes,
+        }),
+    )
+}
+
+fn season_to_json(season: &Media<Season>) -> Value {
+    let metadata = &season.metadata;
+    media_to_json(
+        season,
+        "season_metadata",
+        json!({
+            "audio_locales": metadata.audio_locales,
+            "subtitle_locales": metadata.subtitle_locales,
+            "season_number": metadata.season_number,
+            "maturity_ratings": metadata.maturity_ratings,
+            "is_mature": metadata.is_mature,
+            "mature_blocked": metadata.mature_blocked,
+        }),
+    )
+}
+
+fn episode_to_json(episode: &Media<Episode>) -> Value {
+    let metadata = &episode.metadata;
+    media_to_json(
+        episode,
+        "episode_metadata",
+        json!({
+            "series_id": metadata.series_id,
+            "series_title": metadata.series_title,
+            "series_slug_title": metadata.series_slug_title,
+            "season_id": metadata.season_id,
+            "season_title": metadata.season_title,
+            "season_slug_title": metadata.season_slug_title,
+            "season_number": metadata.season_number,
+            "episode": metadata.episode,
+            "episode_number": metadata.episode_number,
+            "sequence_number": metadata.sequence_number,
+            "duration_ms": metadata.duration.num_milliseconds(),
+            "episode_air_date": metadata.episode_air_date.to_rfc3339(),
+            "upload_date": metadata.upload_date.to_rfc3339(),
+            "is_subbed": metadata.is_subbed,
+            "is_dubbed": metadata.is_dubbed,
+            "closed_captions_available": metadata.closed_captions_available,
+            "audio_locale": metadata.audio_locale,
+            "subtitle_locales": metadata.subtitle_locales,
+            "is_clip": metadata.is_clip,
+            "is_premium_only": metadata.is_premium_only,
+            "maturity_ratings": metadata.maturity_ratings,
+            "is_mature": metadata.is_mature,
+            "mature_blocked": metadata.mature_blocked,
+            "available_offline": metadata.available_offline,
+            "availability_notes": metadata.availability_notes,
+            "eligible_region": metadata.eligible_region,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Responses as the api returns them, with a non-default value in every field the cache
+    /// stores.
+    fn series_response() -> Value {
+        json!({
+            "id": "GY8VEQ95Y",
+            "external_id": "SRZ.283389",
+            "channel_id": "crunchyroll",
+            "slug": "",
+            "title": "DARLING in the FRANXX",
+            "slug_title": "darling-in-the-franxx",
+            "promo_title": "Promo",
+            "description": "In the distant future...",
+            "promo_description": "Promo description",
+            "type": "series",
+            "series_metadata": {
+                "extended_description": "Extended description",
+                "series_launch_year": 2018,
+                "episode_count": 24,
+                "season_count": 2,
+                "is_subbed": true,
+                "is_dubbed": true,
+                "is_simulcast": true,
+                "audio_locales": ["ja-JP", "en-US"],
+                "subtitle_locales": ["en-US", "de-DE"],
+                "maturity_ratings": ["TV-14"],
+                "is_mature": true,
+                "mature_blocked": true,
+                "availability_notes": "Notes",
+                "tenant_categories": ["Action"]
+            }
+        })
+    }
+
+    fn season_response() -> Value {
+        json!({
+            "id": "GYE5CQNJ2",
+            "external_id": "SRZ.283389-S1",
+            "channel_id": "crunchyroll",
+            "slug": "darling-in-the-franxx-season-1",
+            "title": "DARLING in the FRANXX",
+            "slug_title": "darling-in-the-franxx",
+            "promo_title": "Promo",
+            "description": "Season description",
+            "promo_description": "Promo description",
+            "season_metadata": {
+                "audio_locales": ["ja-JP"],
+                "subtitle_locales": ["en-US", "de-DE"],
+                "season_number": 2,
+                "maturity_ratings": ["TV-14"],
+                "is_mature": true,
+                "mature_blocked": true
+            }
+        })
+    }
+
+    fn episode_response() -> Value {
+        json!({
+            "id": "GRDQPM1ZY",
+            "external_id": "EPI.587497",
+            "channel_id": "crunchyroll",
+            "slug": "alone-and-lonesome",
+            "title": "Alone and Lonesome",
+            "slug_title": "alone-and-lonesome",
+            "promo_title": "Promo",
+            "description": "Episode description",
+            "promo_description": "Promo description",
+            "__links__": {
+                "episode/season": { "href": "/cms/v2/US/M3/crunchyroll/seasons/GYE5CQNJ2" },
+                "streams": { "href": "/cms/v2/US/M3/crunchyroll/videos/GZJH3DZQM/streams" }
+            },
+            "episode_metadata": {
+                "series_id": "GY8VEQ95Y",
+                "series_title": "DARLING in the FRANXX",
+                "series_slug_title": "darling-in-the-franxx",
+                "season_id": "GYE5CQNJ2",
+                "season_title": "DARLING in the FRANXX",
+                "season_slug_title": "darling-in-the-franxx",
+                "season_number": 2,
+                "episode": "1",
+                "episode_number": 1,
+                "sequence_number": 1.5,
+                "duration_ms": 1420000,
+                "episode_air_date": "2018-01-13T15:30:00+00:00",
+                "upload_date": "2018-01-14T04:00:00+00:00",
+                "is_subbed": true,
+                "is_dubbed": true,
+                "closed_captions_available": true,
+                "audio_locale": "ja-JP",
+                "subtitle_locales": ["en-US", "de-DE"],
+                "is_clip": true,
+                "is_premium_only": true,
+                "maturity_ratings": ["TV-14"],
+                "is_mature": true,
+                "mature_blocked": true,
+                "available_offline": true,
+                "availability_notes": "Notes",
+                "eligible_region": "US"
+            }
+        })
+    }
+
+    /// Check that every value the cache stores equals the one in the original api response. A
+    /// field which is renamed or not deserialized anymore falls back to its default and differs.
+    fn assert_stored_as_received(stored: &Value, response: &Value, path: &str) {
+        match stored {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    // the stream url is shortened, only the stream id is needed
+                    if key == "__links__" {
+                        continue;
+                    }
+                    assert_stored_as_received(value, &response[key], &format!("{}.{}", path, key))
+                }
+            }
+            _ => assert_eq!(stored, response, "{}", path),
+        }
+    }
+
+    #[test]
+    fn series_round_trip() {
+        let response = series_response();
+        let series: Media<Series> = serde_json::from_value(response.clone()).unwrap();
+        let stored = series_to_json(&series);
+        assert_stored_as_received(&stored, &response, "series");
+
+        let cached: Media<Series> = serde_json::from_value(stored.clone()).unwrap();
+        assert_eq!(series_to_json(&cached), stored);
+        assert!(matches!(
+            serde_json::from_value::<MediaCollection>(stored).unwrap(),
+            MediaCollection::Series(_)
+        ))
+    }
+
+    #[test]
+    fn season_round_trip() {
+        let response = season_response();
+        let season: Media<Season> = serde_json::from_value(response.clone()).unwrap();
+        let stored = season_to_json(&season);
+        assert_stored_as_received(&stored, &response, "season");
+
+        let cached: Media<Season> = serde_json::from_value(stored.clone()).unwrap();
+        assert_eq!(season_to_json(&cached), stored);
+        assert!(matches!(
+            serde_json::from_value::<MediaCollection>(stored).unwrap(),
+            MediaCollection::Season(_)
+        ))
+    }
+
+    #[test]
+    fn episode_round_trip() {
+        let response = episode_response();
+        let episode: Media<Episode> = serde_json::from_value(response.clone()).unwrap();
+        let stored = episode_to_json(&episode);
+        assert_stored_as_received(&stored, &response, "episode");
+
+        let cached: Media<Episode> = serde_json::from_value(stored.clone()).unwrap();
+        assert_eq!(episode_to_json(&cached), stored);
+        assert_eq!(cached.stream_id, Some("GZJH3DZQM".to_string()));
+        assert_eq!(cached.metadata.duration.num_milliseconds(), 1420000);
+        assert_eq!(
+            cached.metadata.episode_air_date,
+            episode.metadata.episode_air_date
+        );
+        assert!(matches!(
+            serde_json::from_value::<MediaCollection>(stored).unwrap(),
+            MediaCollection::Episode(_)
+        ))
+    }
+
+    fn cache(mode: CacheMode, ttl: Duration, dir: &tempfile::TempDir) -> MetadataCache {
+        MetadataCache {
+            mode,
+            ttl,
+            dir: Some(dir.path().to_path_buf()),
+        }
+    }
+
+    #[test]
+    fn read_written_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(CacheMode::Enabled, Duration::from_secs(60), &dir);
+
+        cache.write("episodes_GYE5CQNJ2", json!([episode_response()]));
+        let episodes: Vec<Media<Episode>> = cache.read("episodes_GYE5CQNJ2").unwrap();
+        assert_eq!(episodes.len(), 1);
+        assert_eq!(episodes[0].id, "GRDQPM1ZY");
+        assert!(cache
+            .read::<Vec<Media<Episode>>>("episodes_other")
+            .is_none())
+    }
+
+    #[test]
+    fn expired_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(CacheMode::Enabled, Duration::ZERO, &dir);
+
+        fs::write(
+            dir.path().join("media_GYE5CQNJ2.json"),
+            json!({ "created": 0, "value": season_response() }).to_string(),
+        )
+        .unwrap();
+        assert!(cache.read::<Media<Season>>("media_GYE5CQNJ2").is_none())
+    }
+
+    #[test]
+    fn corrupt_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = cache(CacheMode::Enabled, Duration::from_secs(60), &dir);
+
+        fs::write(dir.path().join("media_GYE5CQNJ2.json"), "{\"created\":").unwrap();
+        assert!(cache.read::<Media<Season>>("media_GYE5CQNJ2").is_none())
+    }
+
+    #[test]
+    fn refresh_and_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let refresh = cache(CacheMode::Refresh, Duration::from_secs(60), &dir);
+        refresh.write("media_GYE5CQNJ2", season_response());
+        assert!(refresh.read::<Media<Season>>("media_GYE5CQNJ2").is_none());
+        assert!(cache(CacheMode::Enabled, Duration::from_secs(60), &dir)
+            .read::<Media<Season>>("media_GYE5CQNJ2")
+            .is_some());
+
+        let disabled = cache(CacheMode::Disabled, Duration::from_secs(60), &dir);
+        disabled.write("media_GY8VEQ95Y", series_response());
+        assert!(!dir.path().join("media_GY8VEQ95Y.json").exists())
+    }
+}
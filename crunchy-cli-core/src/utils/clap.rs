@@ -1,6 +1,52 @@
-use crate::utils::parse::parse_resolution;
+use crate::utils::parse::{parse_rate, parse_resolution};
 use crunchyroll_rs::media::Resolution;
 
 pub fn clap_parse_resolution(s: &str) -> Result<Resolution, String> {
     parse_resolution(s.to_string()).map_err(|e| e.to_string())
 }
+
+pub fn clap_parse_rate(s: &str) -> Result<u64, String> {
+    parse_rate(s.to_string()).map_err(|e| e.to_string())
+}
+
+/// Args in shell syntax, split into the single args.
+#[derive(Clone, Debug)]
+pub struct ShellArgs(pub Vec<String>);
+
+pub fn clap_parse_shell_args(s: &str) -> Result<ShellArgs, String> {
+    shlex::split(s)
+        .map(ShellArgs)
+        .ok_or_else(|| format!("Failed to parse arguments '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate() {
+        assert_eq!(clap_parse_rate("500").unwrap(), 500);
+        assert_eq!(clap_parse_rate("500K").unwrap(), 500 * 1024);
+        assert_eq!(clap_parse_rate("1.5MB/s").unwrap(), 3 * 512 * 1024);
+        for rate in [
+            "inf", "INFINITY", "nan", "infK", "1e3", "-1", "0", "0.1", "K", "",
+        ] {
+            assert!(clap_parse_rate(rate).is_err(), "{}", rate)
+        }
+    }
+
+    #[test]
+    fn shell_args() {
+        assert_eq!(
+            clap_parse_shell_args("-threads 4 -metadata 'title=a b'")
+                .unwrap()
+                .0,
+            ["-threads", "4", "-metadata", "title=a b"]
+        );
+        assert!(clap_parse_shell_args("").unwrap().0.is_empty());
+        assert_eq!(
+            clap_parse_shell_args("-metadata 'title").unwrap_err(),
+            "Failed to parse arguments '-metadata 'title'"
+        )
+    }
+}
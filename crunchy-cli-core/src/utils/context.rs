@@ -1,5 +1,31 @@
+use crate::utils::cache::MetadataCache;
+use crate::utils::rate_limit::RateLimiter;
+use crate::utils::retry::RetryPolicy;
 use crunchyroll_rs::Crunchyroll;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
 
 pub struct Context {
     pub crunchy: Crunchyroll,
+    /// Number of episodes which are downloaded at the same time.
+    pub jobs: usize,
+    /// Number of episodes which are converted with ffmpeg at the same time.
+    pub encode_jobs: usize,
+    /// Number of segments which are downloaded at the same time.
+    pub concurrent_segments: usize,
+    /// Every segment download holds one permit while it's running. The semaphore is shared between
+    /// all episodes which are downloaded at the same time, so together they do not exceed
+    /// `concurrent_segments`.
+    pub segment_slots: Arc<Semaphore>,
+    /// Number of api requests which are made at the same time while resolving series, seasons and
+    /// movie listings.
+    pub concurrent_requests: usize,
+    /// Maximum number of segments which are downloaded but not yet written.
+    pub segment_window: usize,
+    /// Limits the throughput of all downloads (segments and subtitles) together.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// How failed requests (segments, subtitles and api calls) are retried.
+    pub retry: RetryPolicy,
+    /// Cache of series, season and episode metadata.
+    pub cache: MetadataCache,
 }
@@ -0,0 +1,255 @@
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use log::debug;
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+/// Oldest ffmpeg version which is known to work.
+const MIN_VERSION: (u32, u32) = (4, 0);
+
+static FFMPEG_BINARY: OnceLock<FFmpegBinary> = OnceLock::new();
+
+lazy_static! {
+    static ref FFMPEG_CAPABILITIES: Option<FFmpegCapabilities> = FFmpegCapabilities::probe();
+}
+
+/// The ffmpeg binary and the args every ffmpeg call starts with.
+struct FFmpegBinary {
+    path: PathBuf,
+    global_args: Vec<String>,
+}
+
+/// Set the ffmpeg binary (default is `ffmpeg` from `PATH`) and the args every ffmpeg call starts
+/// with (e.g. `-threads 4`). Must be called before ffmpeg is used the first time, later calls are
+/// ignored.
+pub fn init_ffmpeg(path: Option<PathBuf>, global_args: Vec<String>) {
+    let _ = FFMPEG_BINARY.set(FFmpegBinary {
+        path: path.unwrap_or(PathBuf::from("ffmpeg")),
+        global_args,
+    });
+}
+
+fn ffmpeg_binary() -> &'static FFmpegBinary {
+    FFMPEG_BINARY.get_or_init(|| FFmpegBinary {
+        path: PathBuf::from("ffmpeg"),
+        global_args: vec![],
+    })
+}
+
+/// The ffprobe binary. If ffmpeg is set to a specific path, the ffprobe next to it is used (if it
+/// exists).
+pub fn ffprobe_path() -> PathBuf {
+    let ffmpeg_path = &ffmpeg_binary().path;
+    let ffprobe_name = ffmpeg_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .replacen("ffmpeg", "ffprobe", 1);
+    match ffmpeg_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && dir.join(&ffprobe_name).exists() => {
+            dir.join(ffprobe_name)
+        }
+        _ => PathBuf::from("ffprobe"),
+    }
+}
+
+/// The full ffmpeg command line with the configured binary and global args, as it is executed by
+/// [`ffmpeg_command`].
+pub fn ffmpeg_command_line(args: &[String]) -> String {
+    let binary = ffmpeg_binary();
+    let mut command_line = vec![binary.path.to_string_lossy().to_string()];
+    command_line.extend(binary.global_args.clone());
+    command_line.extend(args.iter().cloned());
+    // quoting only fails if an arg contains a nul byte, which cannot be executed anyway
+    shlex::try_join(command_line.iter().map(|a| a.as_str()))
+        .unwrap_or_else(|_| command_line.join(" "))
+}
+
+/// Create a ffmpeg command with the configured binary, global args and the given args. Every
+/// ffmpeg process (except the capability probe) must be created with this.
+pub fn ffmpeg_command(args: &[String]) -> Command {
+    debug!("Executing {}", ffmpeg_command_line(args));
+
+    let binary = ffmpeg_binary();
+    let mut command = Command::new(&binary.path);
+    command.args(&binary.global_args).args(args);
+    command
+}
+
+/// Version and compiled in encoders, decoders and hardware accelerations of the installed ffmpeg.
+pub struct FFmpegCapabilities {
+    pub version: String,
+    encoders: HashSet<String>,
+    decoders: HashSet<String>,
+    hwaccels: HashSet<String>,
+}
+
+impl FFmpegCapabilities {
+    fn probe() -> Option<Self> {
+        let version = ffmpeg_output(&["-version"])?;
+        let version = version
+            .lines()
+            .next()
+            .and_then(|l| l.strip_prefix("ffmpeg version "))
+            .and_then(|l| l.split_whitespace().next())
+            .unwrap_or_default()
+            .to_string();
+
+        let capabilities = Self {
+            version,
+            encoders: parse_codecs(&ffmpeg_output(&["-encoders"])?),
+            decoders: parse_codecs(&ffmpeg_output(&["-decoders"])?),
+            hwaccels: ffmpeg_output(&["-hwaccels"])?
+                .lines()
+                .skip_while(|l| !l.starts_with("Hardware acceleration methods:"))
+                .skip(1)
+                .map(|l| l.trim().to_string())
+                .filter(|l| !l.is_empty())
+                .collect(),
+        };
+        debug!(
+            "Found ffmpeg {} ({} encoders, {} decoders, hardware accelerations: {})",
+            capabilities.version,
+            capabilities.encoders.len(),
+            capabilities.decoders.len(),
+            capabilities
+                .hwaccels
+                .iter()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        Some(capabilities)
+    }
+
+    /// Check if the ffmpeg version is supported and if every encoder, decoder and hardware
+    /// acceleration used in the given input and output args is available.
+    pub fn validate(&self, input_args: &[String], output_args: &[String]) -> Result<()> {
+        if let Some(version) = self.parsed_version() {
+            if version < MIN_VERSION {
+                bail!(
+                    "FFmpeg {} is too old, at least {}.{} is needed",
+                    self.version,
+                    MIN_VERSION.0,
+                    MIN_VERSION.1
+                )
+            }
+        } else {
+            // development builds have versions like 'N-111111-g1234567'
+            debug!("Unknown ffmpeg version format '{}'", self.version)
+        }
+
+        for (option, value) in codec_args(input_args) {
+            if option == "-hwaccel" {
+                // 'cuvid' is an alias of 'cuda' in newer ffmpeg versions
+                if value != "auto"
+                    && !self.hwaccels.contains(value)
+                    && !(value == "cuvid" && self.hwaccels.contains("cuda"))
+                {
+                    bail!("The installed ffmpeg doesn't support the hardware acceleration '{}' (ffmpeg {})", value, self.version)
+                }
+            } else if !self.decoders.contains(value) {
+                bail!(
+                    "The installed ffmpeg doesn't include the decoder '{}' (ffmpeg {})",
+                    value,
+                    self.version
+                )
+            }
+        }
+        for (option, value) in codec_args(output_args) {
+            if option != "-hwaccel" && !self.encoders.contains(value) {
+                bail!(
+                    "The installed ffmpeg doesn't include the encoder '{}' (ffmpeg {})",
+                    value,
+                    self.version
+                )
+            }
+        }
+
+        Ok(())
+    }
+
+    fn parsed_version(&self) -> Option<(u32, u32)> {
+        let mut parts = self
+            .version
+            .trim_start_matches('n')
+            .split(|c: char| !c.is_ascii_digit());
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next().and_then(|m| m.parse().ok()).unwrap_or(0);
+        Some((major, minor))
+    }
+}
+
+/// Get the capabilities of the installed ffmpeg. They're only probed once. `None` if ffmpeg is
+/// not installed.
+pub fn ffmpeg_capabilities() -> Option<&'static FFmpegCapabilities> {
+    FFMPEG_CAPABILITIES.as_ref()
+}
+
+pub fn has_ffmpeg() -> bool {
+    ffmpeg_capabilities().is_some()
+}
+
+fn ffmpeg_path() -> &'static Path {
+    &ffmpeg_binary().path
+}
+
+fn ffmpeg_output(args: &[&str]) -> Option<String> {
+    let output = match Command::new(ffmpeg_path())
+        .arg("-hide_banner")
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            if ErrorKind::NotFound != e.kind() {
+                debug!(
+                    "unknown error occurred while checking if ffmpeg exists: {}",
+                    e.kind()
+                )
+            } else {
+                debug!("ffmpeg not found at {}", ffmpeg_path().to_string_lossy())
+            }
+            return None;
+        }
+    };
+    if !output.status.success() {
+        debug!("ffmpeg {} failed with {}", args.join(" "), output.status);
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Parse the codec names of `ffmpeg -encoders` / `ffmpeg -decoders`. The codecs are listed after
+/// a ` ------` line, every line starts with the codec flags, followed by the name.
+fn parse_codecs(output: &str) -> HashSet<String> {
+    output
+        .lines()
+        .skip_while(|l| !l.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(|c| c.to_string())
+        .collect()
+}
+
+/// All codec (and hardware acceleration) options and their values in the given ffmpeg args.
+/// `copy` is not a codec and therefore skipped.
+fn codec_args(args: &[String]) -> Vec<(&str, &str)> {
+    args.windows(2)
+        .filter(|w| {
+            let option = w[0].as_str();
+            (option == "-c"
+                || option.starts_with("-c:")
+                || option == "-codec"
+                || option.starts_with("-codec:")
+                || ["-vcodec", "-acodec", "-scodec", "-hwaccel"].contains(&option))
+                && w[1] != "copy"
+        })
+        .map(|w| (w[0].as_str(), w[1].as_str()))
+        .collect()
+}
@@ -1,8 +1,15 @@
-use crunchyroll_rs::media::{StreamSubtitle, VariantData};
+use crate::utils::context::Context;
+use anyhow::{bail, Result};
+use crunchyroll_rs::media::{StreamSubtitle, VariantData, VideoStream};
 use crunchyroll_rs::{Episode, Locale, Media, Movie};
-use log::warn;
+use log::{debug, warn};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How long the stream urls of a format are used before they are fetched again. They are valid
+/// for longer, but a download which starts shortly before they expire may take longer than the
+/// rest of their lifetime.
+const STREAM_REUSE_DURATION: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Clone)]
 pub struct Format {
@@ -14,6 +21,13 @@ pub struct Format {
 
     pub duration: Duration,
     pub stream: VariantData,
+    /// Id of the stream the variant belongs to. Used to fetch the stream again if its urls have
+    /// expired.
+    pub stream_id: String,
+    /// Locale of the subtitles which are burned into the stream.
+    pub hardsub: Option<Locale>,
+    /// When the stream (and subtitle) urls were fetched.
+    pub resolved_at: Instant,
 
     pub series_id: String,
     pub series_name: String,
@@ -43,6 +57,9 @@ impl Format {
 
             duration: episode.metadata.duration.to_std().unwrap(),
             stream,
+            stream_id: episode.stream_id.clone().unwrap_or(episode.id.clone()),
+            hardsub: None,
+            resolved_at: Instant::now(),
 
             series_id: episode.metadata.series_id.clone(),
             series_name: episode.metadata.series_title.clone(),
@@ -72,7 +89,11 @@ impl Format {
         }
     }
 
-    pub fn new_from_movie(movie: &Media<Movie>, stream: VariantData) -> Self {
+    pub fn new_from_movie(
+        movie: &Media<Movie>,
+        stream: VariantData,
+        hardsub: Option<Locale>,
+    ) -> Self {
         Self {
             title: movie.title.clone(),
             description: movie.description.clone(),
@@ -81,6 +102,9 @@ impl Format {
 
             duration: movie.metadata.duration.to_std().unwrap(),
             stream,
+            stream_id: movie.stream_id.clone().unwrap_or(movie.id.clone()),
+            hardsub,
+            resolved_at: Instant::now(),
             subtitles: vec![],
 
             series_id: movie.metadata.movie_listing_id.clone(),
@@ -136,6 +160,62 @@ impl Format {
         )
     }
 
+    /// Estimated size of the stream in bytes, calculated from its bandwidth and the video duration.
+    pub fn estimated_size(&self) -> u64 {
+        (self.stream.bandwidth / 8) * self.duration.as_secs()
+    }
+
+    /// If the stream urls were fetched recently enough to be used without fetching them again with
+    /// [`Format::refresh_stream`].
+    pub fn is_stream_fresh(&self) -> bool {
+        self.resolved_at.elapsed() < STREAM_REUSE_DURATION
+    }
+
+    /// Fetch the stream and subtitles of this format again. Their urls are signed and expire after
+    /// some time, which can be the case if the format was resolved long before it is downloaded.
+    /// The returned stream can be used to refresh other urls of the same episode.
+    pub async fn refresh_stream(&mut self, ctx: &Context) -> Result<VideoStream> {
+        let streams = ctx
+            .retry
+            .api("streams", || {
+                VideoStream::from_id(&ctx.crunchy, self.stream_id.clone())
+            })
+            .await?;
+        let variants = ctx
+            .retry
+            .api("streaming data", || {
+                streams.hls_streaming_data(self.hardsub.clone())
+            })
+            .await?;
+
+        let Some(stream) = variants
+            .into_iter()
+            .filter(|v| {
+                v.resolution.width == self.stream.resolution.width
+                    && v.resolution.height == self.stream.resolution.height
+            })
+            .min_by_key(|v| v.bandwidth.abs_diff(self.stream.bandwidth))
+        else {
+            bail!(
+                "Resolution ({}) is no longer available for {}",
+                self.stream.resolution,
+                self.title
+            )
+        };
+        self.stream = stream;
+        self.resolved_at = Instant::now();
+
+        for subtitle in self.subtitles.iter_mut() {
+            if let Some(refreshed) = streams.subtitles.get(&subtitle.locale) {
+                *subtitle = refreshed.clone()
+            }
+        }
+
+        debug!("Refreshed stream urls of {} ({})", self.title, self.audio);
+
+        Ok(streams)
+    }
+
     pub fn has_relative_episodes_fmt<S: AsRef<str>>(s: S) -> bool {
         return s.as_ref().contains("{relative_episode_number}");
     }
@@ -2,12 +2,14 @@ use log::info;
 
 pub struct ProgressHandler {
     pub(crate) stopped: bool,
+    /// Name of the stage the progress represents. Only used for machine readable output.
+    pub(crate) stage: Option<&'static str>,
 }
 
 impl Drop for ProgressHandler {
     fn drop(&mut self) {
         if !self.stopped {
-            info!(target: "progress_end", "")
+            self.end("")
         }
     }
 }
@@ -15,15 +17,29 @@ impl Drop for ProgressHandler {
 impl ProgressHandler {
     pub(crate) fn stop<S: AsRef<str>>(mut self, msg: S) {
         self.stopped = true;
-        info!(target: "progress_end", "{}", msg.as_ref())
+        self.end(msg.as_ref())
+    }
+
+    fn end(&self, msg: &str) {
+        if let Some(stage) = self.stage {
+            info!(target: "progress_end", stage = stage; "{}", msg)
+        } else {
+            info!(target: "progress_end", "{}", msg)
+        }
     }
 }
 
 macro_rules! progress {
+    (stage = $stage:expr; $($arg:tt)+) => {
+        {
+            log::info!(target: "progress", stage = $stage; $($arg)+);
+            $crate::utils::log::ProgressHandler{stopped: false, stage: Some($stage)}
+        }
+    };
     ($($arg:tt)+) => {
         {
             log::info!(target: "progress", $($arg)+);
-            $crate::utils::log::ProgressHandler{stopped: false}
+            $crate::utils::log::ProgressHandler{stopped: false, stage: None}
         }
     }
 }
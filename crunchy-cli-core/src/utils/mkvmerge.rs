@@ -0,0 +1,67 @@
+use log::debug;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+
+static MKVMERGE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set the mkvmerge binary (default is `mkvmerge` from `PATH`). Must be called before mkvmerge is
+/// used the first time, later calls are ignored.
+pub fn init_mkvmerge(path: Option<PathBuf>) {
+    let _ = MKVMERGE_PATH.set(path.unwrap_or(PathBuf::from("mkvmerge")));
+}
+
+fn mkvmerge_path() -> &'static Path {
+    MKVMERGE_PATH.get_or_init(|| PathBuf::from("mkvmerge"))
+}
+
+/// Check if mkvmerge (part of MKVToolNix) is installed.
+pub fn has_mkvmerge() -> bool {
+    match Command::new(mkvmerge_path())
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) => {
+            debug!(
+                "Found {}",
+                String::from_utf8_lossy(&output.stdout).trim_end()
+            );
+            output.status.success()
+        }
+        Err(e) => {
+            if ErrorKind::NotFound != e.kind() {
+                debug!(
+                    "unknown error occurred while checking if mkvmerge exists: {}",
+                    e.kind()
+                )
+            } else {
+                debug!(
+                    "mkvmerge not found at {}",
+                    mkvmerge_path().to_string_lossy()
+                )
+            }
+            false
+        }
+    }
+}
+
+/// The full mkvmerge command line, as it is executed by [`mkvmerge_command`].
+pub fn mkvmerge_command_line(args: &[String]) -> String {
+    let mut command_line = vec![mkvmerge_path().to_string_lossy().to_string()];
+    command_line.extend(args.iter().cloned());
+    // quoting only fails if an arg contains a nul byte, which cannot be executed anyway
+    shlex::try_join(command_line.iter().map(|a| a.as_str()))
+        .unwrap_or_else(|_| command_line.join(" "))
+}
+
+/// Create a mkvmerge command with the given args.
+pub fn mkvmerge_command(args: &[String]) -> Command {
+    debug!("Executing {}", mkvmerge_command_line(args));
+
+    let mut command = Command::new(mkvmerge_path());
+    command.args(args);
+    command
+}
@@ -1,10 +1,17 @@
+pub mod batch;
+pub mod cache;
 pub mod clap;
 pub mod context;
+pub mod ffmpeg;
 pub mod format;
 pub mod locale;
 pub mod log;
+pub mod mkvmerge;
 pub mod os;
 pub mod parse;
+pub mod probe;
+pub mod rate_limit;
+pub mod report;
+pub mod retry;
 pub mod sort;
 pub mod subtitle;
-pub mod video;
@@ -1,24 +1,10 @@
+use indicatif::HumanBytes;
 use log::debug;
-use std::io::ErrorKind;
+use std::collections::{BTreeMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
 use std::{env, io};
 use tempfile::{Builder, NamedTempFile};
 
-pub fn has_ffmpeg() -> bool {
-    if let Err(e) = Command::new("ffmpeg").stderr(Stdio::null()).spawn() {
-        if ErrorKind::NotFound != e.kind() {
-            debug!(
-                "unknown error occurred while checking if ffmpeg exists: {}",
-                e.kind()
-            )
-        }
-        false
-    } else {
-        true
-    }
-}
-
 /// Any tempfile should be created with this function. The prefix and directory of every file
 /// created with this method stays the same which is helpful to query all existing tempfiles and
 /// e.g. remove them in a case of ctrl-c. Having one function also good to prevent mistakes like
@@ -36,14 +22,16 @@ pub fn tempfile<S: AsRef<str>>(suffix: S) -> io::Result<NamedTempFile> {
 }
 
 /// Check if the given path exists and rename it until the new (renamed) file does not exist.
-pub fn free_file(mut path: PathBuf) -> (PathBuf, bool) {
+/// Every path in `reserved` is treated as if it would already exist. This is used to get the same
+/// file names a real run would produce without actually creating any file (e.g. in a dry run).
+pub fn free_file(mut path: PathBuf, reserved: &HashSet<PathBuf>) -> (PathBuf, bool) {
     // if it's a special file does not rename it
     if is_special_file(&path) {
         return (path, false);
     }
 
     let mut i = 0;
-    while path.exists() {
+    while path.exists() || reserved.contains(&path) {
         i += 1;
 
         let ext = path.extension().unwrap_or_default().to_string_lossy();
@@ -63,3 +51,72 @@ pub fn free_file(mut path: PathBuf) -> (PathBuf, bool) {
 pub fn is_special_file<P: AsRef<Path>>(path: P) -> bool {
     path.as_ref().exists() && !path.as_ref().is_file() && !path.as_ref().is_dir()
 }
+
+/// Check if the temp directory can hold `temp_size` bytes and the filesystems of the given output
+/// files can hold their (estimated) sizes. Outputs on the same filesystem as the temp directory
+/// share its free space. Returns a human readable message for every directory which has too
+/// little space.
+pub fn insufficient_space(temp_size: u64, outputs: Vec<(PathBuf, u64)>) -> io::Result<Vec<String>> {
+    // key is a filesystem identifier, value the directory which is checked and the needed space
+    let mut required: BTreeMap<String, (PathBuf, u64)> = BTreeMap::new();
+
+    let mut entries = vec![(env::temp_dir(), temp_size)];
+    entries.extend(outputs.into_iter().map(|(path, size)| {
+        let dir = path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map_or_else(|| PathBuf::from("."), |p| p.to_path_buf());
+        (dir, size)
+    }));
+
+    let current_dir = env::current_dir()?;
+    for (dir, size) in entries {
+        // the output directory may not exist yet, so the nearest existing ancestor is checked. a
+        // relative path must be made absolute first, otherwise its last ancestor is an empty path
+        // which does not exist
+        let dir = current_dir.join(dir);
+        let Some(dir) = dir.ancestors().find(|p| p.exists()) else {
+            continue;
+        };
+        let dir = dir.to_path_buf();
+        let key = filesystem_key(&dir).unwrap_or_else(|| dir.to_string_lossy().to_string());
+        required.entry(key).or_insert_with(|| (dir, 0)).1 += size;
+    }
+
+    let mut messages = vec![];
+    for (dir, size) in required.into_values() {
+        let available = fs2::available_space(&dir)?;
+        debug!(
+            "'{}' needs {} bytes, {} bytes are available",
+            dir.to_string_lossy(),
+            size,
+            available
+        );
+        if size > available {
+            messages.push(format!(
+                "'{}' needs ~{} but only {} are available",
+                dir.to_string_lossy(),
+                HumanBytes(size),
+                HumanBytes(available)
+            ))
+        }
+    }
+
+    Ok(messages)
+}
+
+#[cfg(unix)]
+fn filesystem_key(path: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    path.metadata().ok().map(|m| m.dev().to_string())
+}
+
+#[cfg(not(unix))]
+fn filesystem_key(path: &Path) -> Option<String> {
+    // the drive / prefix of the path (e.g. 'C:')
+    path.canonicalize()
+        .ok()?
+        .components()
+        .next()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+}
@@ -1,6 +1,8 @@
+use crate::utils::cache;
+use crate::utils::context::Context;
 use anyhow::{anyhow, bail, Result};
 use crunchyroll_rs::media::Resolution;
-use crunchyroll_rs::{Crunchyroll, MediaCollection, UrlType};
+use crunchyroll_rs::{MediaCollection, UrlType};
 use log::debug;
 use regex::Regex;
 
@@ -64,7 +66,7 @@ impl UrlFilter {
 
 /// In practice, it would look like this: `https://beta.crunchyroll.com/series/12345678/example[S1E5-S3E2]`.
 pub async fn parse_url(
-    crunchy: &Crunchyroll,
+    ctx: &Context,
     mut url: String,
     with_filter: bool,
 ) -> Result<(MediaCollection, UrlFilter)> {
@@ -126,7 +128,7 @@ pub async fn parse_url(
     debug!("Url type: {:?}", parsed_url);
     let media_collection = match parsed_url {
         UrlType::Series(id) | UrlType::MovieListing(id) | UrlType::EpisodeOrMovie(id) => {
-            crunchy.media_collection_from_id(id).await?
+            cache::media_collection(ctx, &id).await?
         }
     };
 
@@ -168,3 +170,33 @@ pub fn parse_resolution(mut resolution: String) -> Result<Resolution> {
         bail!("Could not parse resolution")
     }
 }
+
+/// Parse a rate given as a [`String`] to bytes per second. The rate is a (decimal) number with an
+/// optional `K`, `M` or `G` suffix (1024 based), e.g. `500K` or `1.5M`.
+pub fn parse_rate(rate: String) -> Result<u64> {
+    let rate = rate.trim().to_uppercase();
+    let rate = rate.strip_suffix("/S").unwrap_or(&rate);
+    let rate = rate.strip_suffix('B').unwrap_or(rate);
+
+    let (number, multiplier) = match rate.chars().last() {
+        Some('K') => (&rate[..rate.len() - 1], 1024),
+        Some('M') => (&rate[..rate.len() - 1], 1024 * 1024),
+        Some('G') => (&rate[..rate.len() - 1], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+    // `f64::from_str` also accepts `inf`, `nan` and exponents, only plain decimals are rates
+    if !number.chars().all(|c| c.is_ascii_digit() || c == '.') {
+        bail!("Could not parse rate")
+    }
+    let number: f64 = number
+        .parse()
+        .ok()
+        .filter(|n: &f64| n.is_finite())
+        .ok_or_else(|| anyhow!("Could not parse rate"))?;
+
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        bail!("Rate must be greater than 0")
+    }
+    Ok(bytes)
+}
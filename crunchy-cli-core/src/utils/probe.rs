@@ -0,0 +1,1035 @@
+use crate::utils::ffmpeg::ffprobe_path;
+use anyhow::{anyhow, bail, Result};
+use chrono::NaiveTime;
+use crunchyroll_rs::media::Resolution;
+use log::debug;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+/// Size of a mpeg-ts packet.
+const TS_PACKET_SIZE: usize = 188;
+/// Number of bytes which are read from the start and the end of a mpeg-ts file. The start contains
+/// the stream definitions and the first timestamps, the end the last timestamps.
+const TS_PROBE_SIZE: u64 = 2 * 1024 * 1024;
+/// Clock rate of mpeg-ts timestamps.
+const TS_CLOCK_RATE: f64 = 90_000.0;
+/// Timestamps are 33 bit and wrap around after that.
+const TS_TIMESTAMP_WRAP: u64 = 1 << 33;
+/// Sample rates of aac, indexed by the sampling frequency index of an adts header.
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// Information about a media file.
+#[derive(Clone, Debug)]
+pub struct MediaProbe {
+    pub duration: Duration,
+    pub streams: Vec<StreamProbe>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StreamProbe {
+    pub kind: StreamKind,
+    /// Name of the codec, the same as ffmpeg uses (e.g. `h264` or `aac`).
+    pub codec: String,
+    /// Only known for video streams.
+    pub resolution: Option<Resolution>,
+    /// Only known for video streams.
+    pub fps: Option<f64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamKind {
+    Video,
+    Audio,
+    Subtitle,
+    Data,
+}
+
+impl MediaProbe {
+    pub fn has_stream(&self, kind: StreamKind) -> bool {
+        self.streams.iter().any(|s| s.kind == kind)
+    }
+
+    /// The duration as time of day, which is the format subtitles use.
+    pub fn length(&self) -> NaiveTime {
+        NaiveTime::default()
+            + chrono::Duration::from_std(self.duration).unwrap_or(chrono::Duration::zero())
+    }
+}
+
+/// Probe a media file. Mpeg-ts and raw aac (adts) files, which is what segments are downloaded as,
+/// are read directly; everything else, or files which cannot be read, are probed with `ffprobe`.
+pub fn probe(path: &Path) -> Result<MediaProbe> {
+    let probe = match probe_native(path) {
+        Ok(probe) => probe,
+        Err(e) => {
+            debug!(
+                "Could not probe {} natively ({}), falling back to ffprobe",
+                path.to_string_lossy(),
+                e
+            );
+            probe_ffprobe(path)?
+        }
+    };
+
+    debug!(
+        "Probed {}: {:.2}s, {}",
+        path.to_string_lossy(),
+        probe.duration.as_secs_f64(),
+        probe
+            .streams
+            .iter()
+            .map(|stream| {
+                let mut description = format!("{:?} ({})", stream.kind, stream.codec);
+                if let Some(resolution) = &stream.resolution {
+                    description.push_str(&format!(" {}", resolution))
+                }
+                if let Some(fps) = stream.fps {
+                    description.push_str(&format!(" {:.2} FPS", fps))
+                }
+                description
+            })
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    Ok(probe)
+}
+
+fn probe_native(path: &Path) -> Result<MediaProbe> {
+    let mut file = File::open(path)?;
+    let mut magic = [0; 2];
+    file.read_exact(&mut magic)?;
+    file.rewind()?;
+
+    if magic[0] == 0x47 {
+        probe_ts(&mut file)
+    } else if (magic[0] == 0xff && magic[1] & 0xf0 == 0xf0) || &magic == b"ID" {
+        probe_adts(file)
+    } else {
+        bail!("unknown container")
+    }
+}
+
+/// A stream of a mpeg-ts file, identified by its pid.
+#[derive(Default)]
+struct TsStream {
+    stream_type: u8,
+    /// Timestamps of the first bytes of the file.
+    head_pts: Vec<u64>,
+    /// Timestamps of the last bytes of the file.
+    tail_pts: Vec<u64>,
+    /// Payload of the first pes packet, which contains the codec parameters of video streams.
+    first_payload: Vec<u8>,
+    /// If the first pes packet is completely read.
+    first_payload_complete: bool,
+}
+
+fn probe_ts(file: &mut File) -> Result<MediaProbe> {
+    let size = file.metadata()?.len();
+
+    let mut head = vec![];
+    file.by_ref().take(TS_PROBE_SIZE).read_to_end(&mut head)?;
+
+    let mut pmt_pids = vec![];
+    let mut streams: BTreeMap<u16, TsStream> = BTreeMap::new();
+
+    for packet in head.chunks_exact(TS_PACKET_SIZE) {
+        let Some((pid, start, payload)) = parse_ts_packet(packet) else {
+            bail!("invalid mpeg-ts packet")
+        };
+
+        if pid == 0 && start {
+            pmt_pids = parse_pat(payload);
+        } else if pmt_pids.contains(&pid) && start {
+            for (es_pid, stream_type) in parse_pmt(payload) {
+                streams.entry(es_pid).or_default().stream_type = stream_type
+            }
+        } else if let Some(stream) = streams.get_mut(&pid) {
+            if start {
+                if let Some(pts) = parse_pes_pts(payload) {
+                    stream.head_pts.push(pts)
+                }
+                stream.first_payload_complete = !stream.first_payload.is_empty()
+            }
+            if !stream.first_payload_complete && (start || !stream.first_payload.is_empty()) {
+                stream.first_payload.extend_from_slice(payload)
+            }
+        }
+    }
+    if streams.is_empty() {
+        bail!("no streams found")
+    }
+
+    let tail_start = size.saturating_sub(TS_PROBE_SIZE).max(head.len() as u64);
+    let tail_start = tail_start - tail_start % TS_PACKET_SIZE as u64;
+    let mut tail = vec![];
+    file.seek(SeekFrom::Start(tail_start))?;
+    file.read_to_end(&mut tail)?;
+    for packet in tail.chunks_exact(TS_PACKET_SIZE) {
+        let Some((pid, true, payload)) = parse_ts_packet(packet) else {
+            continue;
+        };
+        if let (Some(stream), Some(pts)) = (streams.get_mut(&pid), parse_pes_pts(payload)) {
+            stream.tail_pts.push(pts)
+        }
+    }
+
+    // every stream is unwrapped relative to the same timestamp, otherwise a stream whose
+    // timestamps wrap around while another stream's don't would end up with a wrong offset
+    let Some(reference) = streams.values().find_map(|s| s.head_pts.first().copied()) else {
+        bail!("no timestamps found")
+    };
+
+    let mut start = None;
+    let mut end = None;
+    let mut probe_streams = vec![];
+    for stream in streams.values() {
+        let (kind, codec) = ts_stream_type(stream.stream_type);
+
+        let frame_duration = min_pts_delta(&stream.head_pts).or(min_pts_delta(&stream.tail_pts));
+        if let Some(first) = stream
+            .head_pts
+            .iter()
+            .map(|&pts| unwrap_pts(reference, pts))
+            .min()
+        {
+            let last = stream
+                .tail_pts
+                .iter()
+                .chain(stream.head_pts.iter())
+                .map(|&pts| unwrap_pts(reference, pts))
+                .max()
+                .unwrap_or(first);
+            let stream_end = last + frame_duration.unwrap_or(0) as i64;
+            start = Some(start.map_or(first, |s: i64| s.min(first)));
+            end = Some(end.map_or(stream_end, |e: i64| e.max(stream_end)));
+        }
+
+        let (resolution, fps) = if kind == StreamKind::Video {
+            (
+                (codec == "h264")
+                    .then(|| h264_resolution(&stream.first_payload))
+                    .flatten(),
+                frame_duration.map(|d| TS_CLOCK_RATE / d as f64),
+            )
+        } else {
+            (None, None)
+        };
+
+        probe_streams.push(StreamProbe {
+            kind,
+            codec: codec.to_string(),
+            resolution,
+            fps,
+        })
+    }
+
+    let (Some(start), Some(end)) = (start, end) else {
+        bail!("no timestamps found")
+    };
+    let Ok(duration) = Duration::try_from_secs_f64((end - start) as f64 / TS_CLOCK_RATE) else {
+        bail!("invalid timestamps")
+    };
+    Ok(MediaProbe {
+        duration,
+        streams: probe_streams,
+    })
+}
+
+/// Return the pid, if a pes packet or table starts in it and the payload of a mpeg-ts packet.
+fn parse_ts_packet(packet: &[u8]) -> Option<(u16, bool, &[u8])> {
+    if packet.len() != TS_PACKET_SIZE || packet[0] != 0x47 {
+        return None;
+    }
+    let start = packet[1] & 0x40 != 0;
+    let pid = (((packet[1] & 0x1f) as u16) << 8) | packet[2] as u16;
+    let adaptation_field = packet[3] & 0x20 != 0;
+    let has_payload = packet[3] & 0x10 != 0;
+
+    let offset = if adaptation_field {
+        5 + packet[4] as usize
+    } else {
+        4
+    };
+    if !has_payload || offset >= TS_PACKET_SIZE {
+        return Some((pid, start, &[]));
+    }
+    Some((pid, start, &packet[offset..]))
+}
+
+/// Return the section of a psi table, without the pointer field and crc.
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let table = payload.get(1 + pointer..)?;
+    let section_length = (((*table.get(1)? & 0x0f) as usize) << 8) | *table.get(2)? as usize;
+    // the section length includes the 4 byte crc
+    table.get(..(3 + section_length).checked_sub(4)?)
+}
+
+/// Return the pids of all program map tables of a program association table.
+fn parse_pat(payload: &[u8]) -> Vec<u16> {
+    let Some(section) = psi_section(payload) else {
+        return vec![];
+    };
+    section
+        .get(8..)
+        .unwrap_or_default()
+        .chunks_exact(4)
+        .filter(|program| program[0] != 0 || program[1] != 0)
+        .map(|program| (((program[2] & 0x1f) as u16) << 8) | program[3] as u16)
+        .collect()
+}
+
+/// Return the pid and stream type of every stream of a program map table.
+fn parse_pmt(payload: &[u8]) -> Vec<(u16, u8)> {
+    let Some(section) = psi_section(payload) else {
+        return vec![];
+    };
+    let Some(program_info_length) = section
+        .get(10..12)
+        .map(|l| (((l[0] & 0x0f) as usize) << 8) | l[1] as usize)
+    else {
+        return vec![];
+    };
+
+    let mut streams = vec![];
+    let mut es = section.get(12 + program_info_length..).unwrap_or_default();
+    while es.len() >= 5 {
+        let pid = (((es[1] & 0x1f) as u16) << 8) | es[2] as u16;
+        let es_info_length = (((es[3] & 0x0f) as usize) << 8) | es[4] as usize;
+        streams.push((pid, es[0]));
+        es = es.get(5 + es_info_length..).unwrap_or_default();
+    }
+    streams
+}
+
+/// Return the presentation timestamp of a pes packet header.
+fn parse_pes_pts(payload: &[u8]) -> Option<u64> {
+    if payload.get(..3)? != [0, 0, 1] || *payload.get(7)? & 0x80 == 0 {
+        return None;
+    }
+    let pts = payload.get(9..14)?;
+    Some(
+        (((pts[0] >> 1) & 0x07) as u64) << 30
+            | (pts[1] as u64) << 22
+            | ((pts[2] >> 1) as u64) << 15
+            | (pts[3] as u64) << 7
+            | (pts[4] >> 1) as u64,
+    )
+}
+
+/// Distance of `pts` to `reference`, negative if `pts` is earlier. Takes into account that the
+/// timestamp may have wrapped around between them.
+fn unwrap_pts(reference: u64, pts: u64) -> i64 {
+    let wrap = TS_TIMESTAMP_WRAP as i64;
+    let delta = (pts as i64 - reference as i64).rem_euclid(wrap);
+    if delta > wrap / 2 {
+        delta - wrap
+    } else {
+        delta
+    }
+}
+
+/// Smallest distance between two timestamps, which is the duration of a frame (or a pes packet).
+/// Video frames are not necessarily stored in presentation order, so the timestamps are sorted
+/// first.
+fn min_pts_delta(pts: &[u64]) -> Option<u64> {
+    let mut pts = pts.to_vec();
+    pts.sort_unstable();
+    pts.windows(2)
+        .map(|w| w[1] - w[0])
+        .filter(|&delta| delta > 0)
+        .min()
+}
+
+/// Stream kind and codec name of a mpeg-ts stream type.
+fn ts_stream_type(stream_type: u8) -> (StreamKind, &'static str) {
+    match stream_type {
+        0x01 => (StreamKind::Video, "mpeg1video"),
+        0x02 => (StreamKind::Video, "mpeg2video"),
+        0x1b => (StreamKind::Video, "h264"),
+        0x24 => (StreamKind::Video, "hevc"),
+        0x03 | 0x04 => (StreamKind::Audio, "mp3"),
+        0x0f => (StreamKind::Audio, "aac"),
+        0x11 => (StreamKind::Audio, "aac_latm"),
+        0x81 => (StreamKind::Audio, "ac3"),
+        0x87 => (StreamKind::Audio, "eac3"),
+        0x15 => (StreamKind::Data, "timed_id3"),
+        _ => (StreamKind::Data, "unknown"),
+    }
+}
+
+/// Reads the resolution from the sequence parameter set of a h264 stream.
+fn h264_resolution(payload: &[u8]) -> Option<Resolution> {
+    let header_length = *payload.get(8)? as usize;
+    let data = payload.get(9 + header_length..)?;
+
+    let sps = data
+        .windows(4)
+        .position(|w| w[..3] == [0, 0, 1] && w[3] & 0x1f == 7)
+        .map(|i| &data[i + 4..])?;
+    // emulation prevention bytes (`0x03` after two zero bytes) are not part of the actual data
+    let mut rbsp = Vec::with_capacity(sps.len());
+    for &byte in sps {
+        if byte == 3 && rbsp.ends_with(&[0, 0]) {
+            continue;
+        }
+        rbsp.push(byte)
+    }
+
+    let mut r = BitReader::new(&rbsp);
+    let profile_idc = r.bits(8)?;
+    r.bits(16)?; // constraint flags and level
+    r.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+        chroma_format_idc = r.ue()?;
+        if chroma_format_idc == 3 {
+            r.bits(1)?; // separate_colour_plane_flag
+        }
+        r.ue()?; // bit_depth_luma_minus8
+        r.ue()?; // bit_depth_chroma_minus8
+        r.bits(1)?; // qpprime_y_zero_transform_bypass_flag
+        if r.bits(1)? == 1 {
+            // scaling matrices
+            for i in 0..if chroma_format_idc != 3 { 8 } else { 12 } {
+                if r.bits(1)? == 1 {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let (mut last, mut next) = (8i64, 8i64);
+                    for _ in 0..size {
+                        if next != 0 {
+                            next = (last + r.se()? + 256) % 256
+                        }
+                        if next != 0 {
+                            last = next
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    r.ue()?; // log2_max_frame_num_minus4
+    match r.ue()? {
+        0 => {
+            r.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            r.bits(1)?; // delta_pic_order_always_zero_flag
+            r.se()?; // offset_for_non_ref_pic
+            r.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..r.ue()? {
+                r.se()?; // offset_for_ref_frame
+            }
+        }
+        _ => (),
+    }
+    r.ue()?; // max_num_ref_frames
+    r.bits(1)?; // gaps_in_frame_num_value_allowed_flag
+    let width_in_mbs = r.ue()? as u64 + 1;
+    let height_in_map_units = r.ue()? as u64 + 1;
+    let frame_mbs_only = r.bits(1)? as u64;
+    if frame_mbs_only == 0 {
+        r.bits(1)?; // mb_adaptive_frame_field_flag
+    }
+    r.bits(1)?; // direct_8x8_inference_flag
+
+    let (mut crop_left, mut crop_right, mut crop_top, mut crop_bottom) = (0, 0, 0, 0);
+    if r.bits(1)? == 1 {
+        crop_left = r.ue()? as u64;
+        crop_right = r.ue()? as u64;
+        crop_top = r.ue()? as u64;
+        crop_bottom = r.ue()? as u64;
+    }
+    let (crop_unit_x, crop_unit_y) = match chroma_format_idc {
+        0 => (1, 2 - frame_mbs_only),
+        1 => (2, 2 * (2 - frame_mbs_only)),
+        2 => (2, 2 - frame_mbs_only),
+        _ => (1, 2 - frame_mbs_only),
+    };
+
+    Some(Resolution {
+        width: (width_in_mbs * 16).checked_sub((crop_left + crop_right) * crop_unit_x)?,
+        height: ((2 - frame_mbs_only) * height_in_map_units * 16)
+            .checked_sub((crop_top + crop_bottom) * crop_unit_y)?,
+    })
+}
+
+/// Reads single bits and exponential-golomb coded numbers.
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn bits(&mut self, count: usize) -> Option<u32> {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.data.get(self.position / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.position % 8)) & 1) as u32;
+            self.position += 1
+        }
+        Some(value)
+    }
+
+    fn ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.bits(1)? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.bits(leading_zeros)?)
+    }
+
+    fn se(&mut self) -> Option<i64> {
+        let value = self.ue()? as i64;
+        Some(if value % 2 == 1 {
+            (value + 1) / 2
+        } else {
+            -value / 2
+        })
+    }
+}
+
+/// Probe a raw aac file. Every adts frame contains 1024 samples, so the duration is calculated by
+/// counting the frames.
+fn probe_adts(file: File) -> Result<MediaProbe> {
+    let size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    // hls audio segments may start with an id3 tag (containing the timestamp of the segment)
+    let mut position = 0;
+    let mut id3 = [0; 10];
+    reader.read_exact(&mut id3)?;
+    if &id3[..3] == b"ID3" {
+        // the size is stored as 4 times 7 bit
+        position = 10
+            + id3[6..]
+                .iter()
+                .fold(0, |size, &b| (size << 7) | (b & 0x7f) as u64)
+    }
+
+    let mut sample_rate = None;
+    let mut frames = 0u64;
+    let mut header = [0; 7];
+    while position + header.len() as u64 <= size {
+        reader.seek(SeekFrom::Start(position))?;
+        reader.read_exact(&mut header)?;
+        if header[0] != 0xff || header[1] & 0xf0 != 0xf0 {
+            bail!("invalid adts frame at byte {}", position)
+        }
+
+        let rate = AAC_SAMPLE_RATES
+            .get(((header[2] >> 2) & 0x0f) as usize)
+            .ok_or_else(|| anyhow!("invalid aac sample rate"))?;
+        sample_rate.get_or_insert(*rate);
+        let frame_length =
+            ((header[3] as u64 & 0x03) << 11) | (header[4] as u64) << 3 | (header[5] as u64) >> 5;
+        if frame_length < header.len() as u64 {
+            bail!("invalid adts frame length at byte {}", position)
+        }
+
+        frames += 1;
+        position += frame_length
+    }
+
+    let Some(sample_rate) = sample_rate else {
+        bail!("no adts frames found")
+    };
+    Ok(MediaProbe {
+        duration: Duration::from_secs_f64((frames * 1024) as f64 / sample_rate as f64),
+        streams: vec![StreamProbe {
+            kind: StreamKind::Audio,
+            codec: "aac".to_string(),
+            resolution: None,
+            fps: None,
+        }],
+    })
+}
+
+#[derive(Deserialize)]
+struct FFprobeOutput {
+    format: FFprobeFormat,
+    #[serde(default)]
+    streams: Vec<FFprobeStream>,
+}
+
+#[derive(Deserialize)]
+struct FFprobeFormat {
+    duration: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FFprobeStream {
+    codec_type: Option<String>,
+    codec_name: Option<String>,
+    width: Option<u64>,
+    height: Option<u64>,
+    avg_frame_rate: Option<String>,
+}
+
+fn probe_ffprobe(path: &Path) -> Result<MediaProbe> {
+    let ffprobe = Command::new(ffprobe_path())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .args([
+            "-v",
+            "error",
+            "-of",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path)
+        .output()
+        .map_err(|e| anyhow!("Failed to run ffprobe: {}", e))?;
+    if !ffprobe.status.success() {
+        bail!("ffprobe could not read {}", path.to_string_lossy())
+    }
+
+    let output: FFprobeOutput = serde_json::from_slice(&ffprobe.stdout)?;
+    let Some(probe) = output.into_probe() else {
+        bail!("ffprobe found no duration for {}", path.to_string_lossy())
+    };
+    Ok(probe)
+}
+
+impl FFprobeOutput {
+    /// `None` if the output has no (valid) duration.
+    fn into_probe(self) -> Option<MediaProbe> {
+        let duration = self.format.duration?.parse::<f64>().ok()?;
+
+        Some(MediaProbe {
+            duration: Duration::try_from_secs_f64(duration).ok()?,
+            streams: self
+                .streams
+                .into_iter()
+                .map(|stream| StreamProbe {
+                    kind: match stream.codec_type.as_deref() {
+                        Some("video") => StreamKind::Video,
+                        Some("audio") => StreamKind::Audio,
+                        Some("subtitle") => StreamKind::Subtitle,
+                        _ => StreamKind::Data,
+                    },
+                    codec: stream.codec_name.unwrap_or_else(|| "unknown".to_string()),
+                    resolution: stream
+                        .width
+                        .zip(stream.height)
+                        .map(|(width, height)| Resolution { width, height }),
+                    fps: stream.avg_frame_rate.and_then(|rate| {
+                        let (num, den) = rate.split_once('/')?;
+                        let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+                        (num > 0.0 && den > 0.0).then(|| num / den)
+                    }),
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const VIDEO_PID: u16 = 0x100;
+    const AUDIO_PID: u16 = 0x101;
+
+    /// The 5 byte pts field of a pes header.
+    fn encode_pts(pts: u64) -> [u8; 5] {
+        [
+            0x21 | ((pts >> 29) & 0x0e) as u8,
+            (pts >> 22) as u8,
+            ((pts >> 14) & 0xfe) as u8 | 1,
+            (pts >> 7) as u8,
+            ((pts << 1) & 0xfe) as u8 | 1,
+        ]
+    }
+
+    fn pes(stream_id: u8, pts: u64, data: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0, 0, 1, stream_id, 0, 0, 0x80, 0x80, 5];
+        pes.extend(encode_pts(pts));
+        pes.extend(data);
+        pes
+    }
+
+    fn ts_packet(pid: u16, start: bool, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x47, (start as u8) << 6 | (pid >> 8) as u8, pid as u8, 0x10];
+        packet.extend(payload);
+        packet.resize(TS_PACKET_SIZE, 0xff);
+        packet
+    }
+
+    fn pat(pmt_pid: u16) -> Vec<u8> {
+        // pointer field, table header, transport stream id, version, section numbers, program
+        // number 1 and crc
+        vec![
+            0,
+            0x00,
+            0xb0,
+            13,
+            0,
+            1,
+            0xc1,
+            0,
+            0,
+            0,
+            1,
+            0xe0 | (pmt_pid >> 8) as u8,
+            pmt_pid as u8,
+            0,
+            0,
+            0,
+            0,
+        ]
+    }
+
+    fn pmt(streams: &[(u16, u8)]) -> Vec<u8> {
+        let mut pmt = vec![
+            0,
+            0x02,
+            0xb0,
+            (9 + 5 * streams.len() + 4) as u8,
+            0,
+            1,
+            0xc1,
+            0,
+            0,
+            0xe1,
+            0x00,
+            0xf0,
+            0x00,
+        ];
+        for (pid, stream_type) in streams {
+            pmt.extend([*stream_type, 0xe0 | (pid >> 8) as u8, *pid as u8, 0xf0, 0]);
+        }
+        pmt.extend([0, 0, 0, 0]);
+        pmt
+    }
+
+    /// Writes single bits and exponential-golomb coded numbers, the counterpart to [`BitReader`].
+    #[derive(Default)]
+    struct BitWriter {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, count: usize, value: u32) -> &mut Self {
+            for i in (0..count).rev() {
+                if self.position / 8 == self.data.len() {
+                    self.data.push(0)
+                }
+                let bit = ((value >> i) & 1) as u8;
+                *self.data.last_mut().unwrap() |= bit << (7 - self.position % 8);
+                self.position += 1
+            }
+            self
+        }
+
+        fn ue(&mut self, value: u32) -> &mut Self {
+            let value = value + 1;
+            let length = 32 - value.leading_zeros() as usize;
+            self.bits(length - 1, 0).bits(length, value)
+        }
+
+        fn finish(&mut self) -> Vec<u8> {
+            // rbsp stop bit
+            self.bits(1, 1);
+            self.data.clone()
+        }
+    }
+
+    /// Sequence parameter set (nal unit included) of a baseline profile stream.
+    fn baseline_sps(width_in_mbs: u32, height_in_mbs: u32, crop: Option<[u32; 4]>) -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.bits(8, 66).bits(16, 0x1e).ue(0); // profile, constraints and level, id
+        w.ue(0).ue(0).ue(0); // frame num, poc type 0 and poc lsb
+        w.ue(1).bits(1, 0); // ref frames, gaps
+        w.ue(width_in_mbs - 1).ue(height_in_mbs - 1);
+        w.bits(1, 1).bits(1, 1); // frame_mbs_only, direct_8x8_inference
+        match crop {
+            Some(crop) => {
+                w.bits(1, 1);
+                for c in crop {
+                    w.ue(c);
+                }
+            }
+            None => {
+                w.bits(1, 0);
+            }
+        }
+        w.bits(1, 0); // vui
+        [vec![0, 0, 0, 1, 0x67], w.finish()].concat()
+    }
+
+    /// Sequence parameter set (nal unit included) of a high profile, interlaced stream with
+    /// scaling matrices.
+    fn high_sps() -> Vec<u8> {
+        let mut w = BitWriter::default();
+        w.bits(8, 100).bits(16, 0x28).ue(0); // profile, constraints and level, id
+        w.ue(1).ue(0).ue(0).bits(1, 0); // chroma format, bit depths, transform bypass
+        w.bits(1, 1); // scaling matrix present
+        w.bits(1, 1); // first list present, every delta is 0 (flat 8)
+        for _ in 0..16 {
+            w.ue(0);
+        }
+        for _ in 1..8 {
+            w.bits(1, 0);
+        }
+        w.ue(0).ue(1); // frame num, poc type 1
+        w.bits(1, 0).ue(0).ue(0).ue(2).ue(1).ue(2); // poc type 1 fields with 2 ref frames
+        w.ue(4).bits(1, 0); // ref frames, gaps
+        w.ue(1920 / 16 - 1).ue(1088 / 32 - 1);
+        w.bits(1, 0).bits(1, 0).bits(1, 1); // interlaced, mbaff, direct_8x8_inference
+        w.bits(1, 0).bits(1, 0); // cropping, vui
+        [vec![0, 0, 1, 0x67], w.finish()].concat()
+    }
+
+    fn ts_file(packets: &[Vec<u8>]) -> File {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&packets.concat()).unwrap();
+        file.rewind().unwrap();
+        file
+    }
+
+    fn adts_frame(sampling_frequency_index: u8, length: usize) -> Vec<u8> {
+        let mut frame = vec![
+            0xff,
+            0xf1,
+            0x40 | sampling_frequency_index << 2,
+            0x80 | (length >> 11) as u8,
+            (length >> 3) as u8,
+            ((length & 0x07) as u8) << 5 | 0x1f,
+            0xfc,
+        ];
+        frame.resize(length, 0);
+        frame
+    }
+
+    #[test]
+    fn pes_pts() {
+        for pts in [0, 1, 900_000, (1 << 32) + 12345, TS_TIMESTAMP_WRAP - 1] {
+            assert_eq!(parse_pes_pts(&pes(0xe0, pts, &[])), Some(pts));
+        }
+
+        let mut without_pts = pes(0xe0, 0, &[]);
+        without_pts[7] = 0;
+        assert_eq!(parse_pes_pts(&without_pts), None);
+        assert_eq!(parse_pes_pts(&[0, 0, 2, 0xe0, 0, 0, 0x80, 0x80, 5]), None);
+        assert_eq!(
+            parse_pes_pts(&[0, 0, 1, 0xe0, 0, 0, 0x80, 0x80, 5, 0x21]),
+            None
+        )
+    }
+
+    #[test]
+    fn pts_wrap() {
+        assert_eq!(unwrap_pts(1000, 4000), 3000);
+        assert_eq!(unwrap_pts(4000, 1000), -3000);
+        assert_eq!(unwrap_pts(TS_TIMESTAMP_WRAP - 1000, 500), 1500);
+        assert_eq!(unwrap_pts(500, TS_TIMESTAMP_WRAP - 1000), -1500);
+    }
+
+    #[test]
+    fn ts_duration() {
+        let mut packets = vec![
+            ts_packet(0, true, &pat(0x1000)),
+            ts_packet(0x1000, true, &pmt(&[(VIDEO_PID, 0x1b), (AUDIO_PID, 0x0f)])),
+        ];
+        // 25 fps video, one pes packet per frame
+        let sps = baseline_sps(120, 68, Some([0, 0, 0, 4]));
+        packets.push(ts_packet(VIDEO_PID, true, &pes(0xe0, 10_000, &sps)));
+        for frame in 1..250 {
+            packets.push(ts_packet(
+                VIDEO_PID,
+                true,
+                &pes(0xe0, 10_000 + frame * 3600, &[]),
+            ))
+        }
+        // audio in pes packets of 0.1 seconds
+        for i in 0..100 {
+            packets.push(ts_packet(
+                AUDIO_PID,
+                true,
+                &pes(0xc0, 10_000 + i * 9000, &[]),
+            ))
+        }
+
+        let probe = probe_ts(&mut ts_file(&packets)).unwrap();
+        assert_eq!(probe.duration, Duration::from_secs(10));
+        assert_eq!(probe.streams.len(), 2);
+        assert_eq!(probe.streams[0].kind, StreamKind::Video);
+        assert_eq!(probe.streams[0].codec, "h264");
+        assert_eq!(
+            probe.streams[0]
+                .resolution
+                .as_ref()
+                .map(|r| (r.width, r.height)),
+            Some((1920, 1080))
+        );
+        assert_eq!(probe.streams[0].fps, Some(25.0));
+        assert_eq!(probe.streams[1].kind, StreamKind::Audio);
+        assert_eq!(probe.streams[1].codec, "aac");
+    }
+
+    #[test]
+    fn ts_duration_wrap() {
+        // the video timestamps wrap around after the first frames, the audio timestamps start
+        // after the wrap
+        let first = TS_TIMESTAMP_WRAP - 5 * 3600;
+        let mut packets = vec![
+            ts_packet(0, true, &pat(0x1000)),
+            ts_packet(0x1000, true, &pmt(&[(VIDEO_PID, 0x1b), (AUDIO_PID, 0x0f)])),
+        ];
+        for frame in 0..50 {
+            let pts = (first + frame * 3600) % TS_TIMESTAMP_WRAP;
+            packets.push(ts_packet(VIDEO_PID, true, &pes(0xe0, pts, &[])))
+        }
+        for i in 0..18 {
+            packets.push(ts_packet(AUDIO_PID, true, &pes(0xc0, i * 9000, &[])))
+        }
+
+        let probe = probe_ts(&mut ts_file(&packets)).unwrap();
+        assert_eq!(probe.duration, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn sps_resolution() {
+        let resolution =
+            |sps: Vec<u8>| h264_resolution(&pes(0xe0, 0, &sps)).map(|r| (r.width, r.height));
+
+        assert_eq!(resolution(baseline_sps(80, 45, None)), Some((1280, 720)));
+        assert_eq!(
+            resolution(baseline_sps(120, 68, Some([0, 0, 0, 4]))),
+            Some((1920, 1080))
+        );
+        assert_eq!(
+            resolution(baseline_sps(40, 30, Some([4, 4, 2, 2]))),
+            Some((624, 472))
+        );
+        assert_eq!(resolution(high_sps()), Some((1920, 1088)));
+        assert_eq!(resolution(pes(0xe0, 0, &[0, 0, 1, 0x65, 0x88])), None);
+    }
+
+    #[test]
+    fn sps_emulation_prevention() {
+        // 0x00 0x00 0x03 inside the sps must be removed before it is parsed
+        let mut w = BitWriter::default();
+        w.bits(8, 66)
+            .bits(16, 0)
+            .ue(0)
+            .ue(0)
+            .ue(0)
+            .ue(0)
+            .ue(1)
+            .bits(1, 0);
+        w.ue(79).ue(44).bits(1, 1).bits(1, 1).bits(1, 0).bits(1, 0);
+        let rbsp = w.finish();
+        assert_eq!(rbsp[1..3], [0, 0]);
+        let sps = [&[0, 0, 1, 0x67, rbsp[0], 0, 0, 3][..], &rbsp[3..]].concat();
+
+        assert_eq!(
+            h264_resolution(&pes(0xe0, 0, &sps)).map(|r| (r.width, r.height)),
+            Some((1280, 720))
+        )
+    }
+
+    #[test]
+    fn adts_duration() {
+        // 3 = 48000 hz
+        let mut file = tempfile::tempfile().unwrap();
+        for _ in 0..375 {
+            file.write_all(&adts_frame(3, 200)).unwrap()
+        }
+        file.rewind().unwrap();
+        let probe = probe_adts(file).unwrap();
+        assert_eq!(probe.duration, Duration::from_secs(8));
+        assert_eq!(probe.streams[0].kind, StreamKind::Audio);
+
+        // 4 = 44100 hz, with an id3 tag in front
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"ID3\x04\x00\x00\x00\x00\x01\x00").unwrap();
+        file.write_all(&[0; 128]).unwrap();
+        for _ in 0..441 {
+            file.write_all(&adts_frame(4, 9)).unwrap()
+        }
+        file.rewind().unwrap();
+        assert_eq!(
+            probe_adts(file).unwrap().duration,
+            Duration::from_secs_f64(441.0 * 1024.0 / 44100.0)
+        );
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&adts_frame(3, 200)).unwrap();
+        file.write_all(&[0; 200]).unwrap();
+        file.rewind().unwrap();
+        assert!(probe_adts(file).is_err())
+    }
+
+    #[test]
+    fn ffprobe_output() {
+        let output: FFprobeOutput = serde_json::from_str(
+            r#"{
+                "streams": [
+                    {"codec_type": "video", "codec_name": "h264", "width": 1920, "height": 1080, "avg_frame_rate": "24000/1001"},
+                    {"codec_type": "audio", "codec_name": "aac", "avg_frame_rate": "0/0"},
+                    {"codec_type": "subtitle", "codec_name": "ass"},
+                    {"codec_type": "attachment"}
+                ],
+                "format": {"duration": "1420.544000"}
+            }"#,
+        )
+        .unwrap();
+        let probe = output.into_probe().unwrap();
+
+        assert_eq!(probe.duration, Duration::from_secs_f64(1420.544));
+        let streams: Vec<_> = probe
+            .streams
+            .iter()
+            .map(|s| {
+                (
+                    s.kind.clone(),
+                    s.codec.as_str(),
+                    s.resolution.as_ref().map(|r| (r.width, r.height)),
+                    s.fps.map(|fps| (fps * 1000.0).round() / 1000.0),
+                )
+            })
+            .collect();
+        assert_eq!(
+            streams,
+            vec![
+                (StreamKind::Video, "h264", Some((1920, 1080)), Some(23.976)),
+                (StreamKind::Audio, "aac", None, None),
+                (StreamKind::Subtitle, "ass", None, None),
+                (StreamKind::Data, "unknown", None, None),
+            ]
+        );
+
+        for format in [
+            r#"{}"#,
+            r#"{"duration": "N/A"}"#,
+            r#"{"duration": "nan"}"#,
+            r#"{"duration": "inf"}"#,
+            r#"{"duration": "-1.5"}"#,
+        ] {
+            let output: FFprobeOutput =
+                serde_json::from_str(&format!(r#"{{"format": {}}}"#, format)).unwrap();
+            assert!(output.into_probe().is_none(), "{}", format)
+        }
+    }
+}
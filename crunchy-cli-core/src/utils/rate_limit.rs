@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Limits the throughput of all downloads which share one instance. It's a token bucket which
+/// holds at most one second of bandwidth, so short pauses do not result in bursts afterwards.
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    state: Mutex<RateLimiterState>,
+}
+
+struct RateLimiterState {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            state: Mutex::new(RateLimiterState {
+                available: bytes_per_second as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second
+    }
+
+    /// Wait until `bytes` may be consumed. The lock is held while waiting, so concurrent callers
+    /// are served one after another and share the bandwidth evenly.
+    pub async fn consume(&self, bytes: usize) {
+        let rate = self.bytes_per_second as f64;
+        let mut state = self.state.lock().await;
+
+        let now = Instant::now();
+        state.available = (state.available
+            + now.duration_since(state.last_refill).as_secs_f64() * rate)
+            .min(rate);
+        state.last_refill = now;
+        state.available -= bytes as f64;
+
+        if state.available < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-state.available / rate)).await
+        }
+    }
+}
+
+/// Read the body of a response. If a rate limiter is given, the body is read in chunks and every
+/// chunk has to pass the limiter.
+pub async fn read_body(
+    mut response: reqwest::Response,
+    rate_limiter: Option<&RateLimiter>,
+) -> reqwest::Result<Vec<u8>> {
+    let Some(rate_limiter) = rate_limiter else {
+        return Ok(response.bytes().await?.to_vec());
+    };
+
+    let mut buf = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        rate_limiter.consume(chunk.len()).await;
+        buf.extend_from_slice(&chunk)
+    }
+    Ok(buf)
+}
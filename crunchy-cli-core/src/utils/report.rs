@@ -0,0 +1,283 @@
+use crate::cli::log::tab_info;
+use crate::utils::format::Format;
+use anyhow::{Error, Result};
+use crunchyroll_rs::{Episode, Media};
+use log::{error, info};
+use serde::Serialize;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportStatus {
+    Succeeded,
+    Skipped,
+    Failed,
+}
+
+impl Display for ReportStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportStatus::Succeeded => f.pad("succeeded"),
+            ReportStatus::Skipped => f.pad("skipped"),
+            ReportStatus::Failed => f.pad("failed"),
+        }
+    }
+}
+
+/// A single processed item (url, season or episode) of a run. Every field except `name` and
+/// `status` is only set if the information is available at the time the item is recorded.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReportItem {
+    pub name: String,
+    pub url: Option<String>,
+    pub episode_id: Option<String>,
+    pub output: Option<PathBuf>,
+
+    pub resolution: Option<String>,
+    pub fps: Option<f64>,
+    pub bandwidth: Option<u64>,
+    pub audio: Vec<String>,
+    pub subtitles: Vec<String>,
+
+    pub bytes_written: Option<u64>,
+    /// Time it took to process the item, in seconds.
+    pub duration: Option<f64>,
+
+    pub status: ReportStatus,
+    #[serde(rename = "message")]
+    pub reason: Option<String>,
+}
+
+impl ReportItem {
+    pub fn new<S: AsRef<str>>(name: S) -> Self {
+        Self {
+            name: name.as_ref().to_string(),
+            url: None,
+            episode_id: None,
+            output: None,
+            resolution: None,
+            fps: None,
+            bandwidth: None,
+            audio: vec![],
+            subtitles: vec![],
+            bytes_written: None,
+            duration: None,
+            status: ReportStatus::Succeeded,
+            reason: None,
+        }
+    }
+
+    pub fn audio<S: ToString>(mut self, audio: Vec<S>) -> Self {
+        self.audio = audio.into_iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    pub fn subtitles<S: ToString>(mut self, subtitles: Vec<S>) -> Self {
+        self.subtitles = subtitles.into_iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Set the output path. If the path is a regular file, its size is used as written bytes.
+    pub fn output<P: AsRef<Path>>(mut self, output: P) -> Self {
+        let output = output.as_ref();
+        if output.is_file() {
+            self.bytes_written = output.metadata().map(|m| m.len()).ok()
+        }
+        self.output = Some(output.to_path_buf());
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = Some(duration.as_secs_f64());
+        self
+    }
+}
+
+impl From<&str> for ReportItem {
+    fn from(name: &str) -> Self {
+        ReportItem::new(name)
+    }
+}
+
+impl From<String> for ReportItem {
+    fn from(name: String) -> Self {
+        ReportItem::new(name)
+    }
+}
+
+impl From<&String> for ReportItem {
+    fn from(name: &String) -> Self {
+        ReportItem::new(name)
+    }
+}
+
+impl From<&Media<Episode>> for ReportItem {
+    fn from(episode: &Media<Episode>) -> Self {
+        let mut item = ReportItem::new(format!(
+            "{} S{:02}E{:02} ({})",
+            episode.metadata.series_title,
+            episode.metadata.season_number,
+            episode.metadata.episode_number,
+            episode.title
+        ));
+        item.episode_id = Some(episode.id.clone());
+        item.audio = vec![episode.metadata.audio_locale.to_string()];
+        item
+    }
+}
+
+impl From<&Format> for ReportItem {
+    fn from(format: &Format) -> Self {
+        let mut item = ReportItem::new(format!(
+            "{} S{:02}E{:02} ({})",
+            format.series_name, format.season_number, format.episode_number, format.title
+        ));
+        item.episode_id = Some(format.episode_id.clone());
+        item.resolution = Some(format.stream.resolution.to_string());
+        item.fps = Some(format.stream.fps);
+        item.bandwidth = Some(format.stream.bandwidth);
+        item.audio = vec![format.audio.to_string()];
+        item.subtitles = format
+            .subtitles
+            .iter()
+            .map(|s| s.locale.to_string())
+            .collect();
+        item
+    }
+}
+
+/// Marks an error which is already recorded in a [`Report`], so that it is not recorded a second
+/// time while it gets propagated upwards.
+struct ReportedError(Error);
+
+impl Debug for ReportedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl Display for ReportedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl std::error::Error for ReportedError {}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    succeeded: usize,
+    skipped: usize,
+    failed: usize,
+    items: &'a Vec<ReportItem>,
+}
+
+/// Collects the result of every processed item (url, season or episode) of a run. If
+/// `continue_on_error` is set, failures are recorded instead of aborting the whole run.
+#[derive(Debug, Default)]
+pub struct Report {
+    continue_on_error: bool,
+    items: Mutex<Vec<ReportItem>>,
+    current_url: Mutex<Option<String>>,
+}
+
+impl Report {
+    pub fn new(continue_on_error: bool) -> Self {
+        Self {
+            continue_on_error,
+            items: Mutex::new(vec![]),
+            current_url: Mutex::new(None),
+        }
+    }
+
+    /// Set the url which is currently processed. It is added to every item which gets recorded
+    /// without an explicit url.
+    pub fn set_current_url<S: AsRef<str>>(&self, url: S) {
+        *self.current_url.lock().unwrap() = Some(url.as_ref().to_string())
+    }
+
+    pub fn succeeded<I: Into<ReportItem>>(&self, item: I) {
+        self.push(item.into(), ReportStatus::Succeeded, None)
+    }
+
+    pub fn skipped<I: Into<ReportItem>, R: ToString>(&self, item: I, reason: R) {
+        self.push(item.into(), ReportStatus::Skipped, Some(reason.to_string()))
+    }
+
+    /// Record a failed item. If the report was not created with `continue_on_error`, the error is
+    /// returned so that it can be propagated with `?`.
+    pub fn failed<I: Into<ReportItem>>(&self, item: I, err: Error) -> Result<()> {
+        if err.is::<ReportedError>() {
+            return Err(err);
+        }
+
+        let item = item.into();
+        let name = item.name.clone();
+        self.push(item, ReportStatus::Failed, Some(err.to_string()));
+
+        if !self.continue_on_error {
+            return Err(ReportedError(err).into());
+        }
+        error!("{} failed: {}", name, err);
+        Ok(())
+    }
+
+    pub fn failed_count(&self) -> usize {
+        self.count(ReportStatus::Failed)
+    }
+
+    pub fn print_summary(&self) {
+        info!(
+            "Summary: {} succeeded, {} skipped, {} failed",
+            self.count(ReportStatus::Succeeded),
+            self.count(ReportStatus::Skipped),
+            self.count(ReportStatus::Failed)
+        );
+        for item in self.items.lock().unwrap().iter() {
+            if let Some(reason) = &item.reason {
+                tab_info!("{:<9} {} ({})", item.status, item.name, reason)
+            } else {
+                tab_info!("{:<9} {}", item.status, item.name)
+            }
+        }
+    }
+
+    /// Write the report as json to the given path.
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let (succeeded, skipped, failed) = (
+            self.count(ReportStatus::Succeeded),
+            self.count(ReportStatus::Skipped),
+            self.count(ReportStatus::Failed),
+        );
+        let items = self.items.lock().unwrap();
+        let json_report = JsonReport {
+            succeeded,
+            skipped,
+            failed,
+            items: &items,
+        };
+        std::fs::write(path, serde_json::to_string_pretty(&json_report)?)?;
+        Ok(())
+    }
+
+    fn count(&self, status: ReportStatus) -> usize {
+        self.items
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|i| i.status == status)
+            .count()
+    }
+
+    fn push(&self, mut item: ReportItem, status: ReportStatus, reason: Option<String>) {
+        if item.url.is_none() {
+            item.url = self.current_url.lock().unwrap().clone()
+        }
+        item.status = status;
+        item.reason = reason;
+        self.items.lock().unwrap().push(item)
+    }
+}
@@ -0,0 +1,357 @@
+use crate::utils::rate_limit::{read_body, RateLimiter};
+use anyhow::{bail, Result};
+use crunchyroll_rs::error::CrunchyrollError;
+use log::debug;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+/// Delay before the first retry. Every following retry waits twice as long as the one before.
+const BASE_DELAY: Duration = Duration::from_millis(500);
+/// Maximum delay between two attempts if the server does not request a specific one.
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// Maximum delay which is accepted from a `Retry-After` header.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Defines how often and how long failed requests are retried. Only failures which may go away on
+/// their own are retried: connection and timeout errors, `429 Too Many Requests` and server errors.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// How often a failed request is retried before giving up.
+    pub retries: u32,
+    /// Timeout of a single attempt.
+    pub timeout: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(retries: u32, timeout: Duration) -> Self {
+        Self { retries, timeout }
+    }
+
+    /// Send the request which is built by `request` and read the response body. The body is read
+    /// through the rate limiter, if one is given. `timeout` overwrites [`RetryPolicy::timeout`],
+    /// which is useful if the request is expected to take longer (e.g. because it is throttled).
+    pub async fn fetch<F>(
+        &self,
+        what: impl Display,
+        request: F,
+        rate_limiter: Option<&RateLimiter>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let timeout = timeout.unwrap_or(self.timeout);
+
+        let mut retry = 0;
+        loop {
+            let (error, retry_after) = match request().timeout(timeout).send().await {
+                Ok(response) => match check_status(response) {
+                    Ok(response) => match read_body(response, rate_limiter).await {
+                        Ok(body) => return Ok(body),
+                        Err(e) if is_transient_request_error(&e) => (e.to_string(), None),
+                        Err(e) => bail!("{}", e),
+                    },
+                    Err(Failure::Transient { error, retry_after }) => (error, retry_after),
+                    Err(Failure::Fatal(error)) => return Err(error.into()),
+                },
+                Err(e) if is_transient_request_error(&e) => (e.to_string(), None),
+                Err(e) => bail!("{}", e),
+            };
+
+            retry += 1;
+            self.wait(&what, retry, error, retry_after).await?
+        }
+    }
+
+    /// Call the Crunchyroll api via `call` and retry it if it fails because of a network or
+    /// server problem. Every attempt is bound to [`RetryPolicy::timeout`].
+    pub async fn api<T, F, Fut>(&self, what: impl Display, mut call: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, CrunchyrollError>>,
+    {
+        let mut retry = 0;
+        loop {
+            let error = match tokio::time::timeout(self.timeout, call()).await {
+                Ok(Ok(result)) => return Ok(result),
+                Ok(Err(e)) if is_transient_api_error(&e) => e.to_string(),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => format!("timed out after {}s", self.timeout.as_secs()),
+            };
+
+            retry += 1;
+            self.wait(&what, retry, error, None).await?
+        }
+    }
+
+    /// Wait before attempt `retry` or fail if no retries are left.
+    async fn wait(
+        &self,
+        what: impl Display,
+        retry: u32,
+        error: String,
+        retry_after: Option<Duration>,
+    ) -> Result<()> {
+        if retry > self.retries {
+            bail!(
+                "Failed to request {} after {} attempts: {}",
+                what,
+                retry,
+                error
+            )
+        }
+
+        let delay = retry_after.unwrap_or_else(|| self.backoff(retry));
+        debug!(
+            "Failed to request {} ({}). Retrying in {:.1}s, {} out of {} retries left",
+            what,
+            error,
+            delay.as_secs_f64(),
+            self.retries - retry,
+            self.retries
+        );
+        tokio::time::sleep(delay).await;
+
+        Ok(())
+    }
+
+    /// Exponential backoff with jitter. The jitter spreads out retries of requests which failed at
+    /// the same time, e.g. all segment downloads if the connection dropped.
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(MAX_DELAY);
+        delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+    }
+}
+
+/// A request which failed with a status code that is not retried.
+#[derive(Debug)]
+pub struct StatusError {
+    pub status: StatusCode,
+    pub url: String,
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.status, self.url)
+    }
+}
+
+impl Error for StatusError {}
+
+enum Failure {
+    Transient {
+        error: String,
+        retry_after: Option<Duration>,
+    },
+    Fatal(StatusError),
+}
+
+fn check_status(response: Response) -> Result<Response, Failure> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let error = StatusError {
+        status,
+        url: response.url().to_string(),
+    };
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        Err(Failure::Transient {
+            error: error.to_string(),
+            retry_after: retry_after(&response),
+        })
+    } else {
+        Err(Failure::Fatal(error))
+    }
+}
+
+fn retry_after(response: &Response) -> Option<Duration> {
+    parse_retry_after(response.headers().get(RETRY_AFTER)?.to_str().ok()?)
+}
+
+/// Parse the value of a `Retry-After` header, which is either a number of seconds or a http date.
+/// The delay is capped at [`MAX_RETRY_AFTER`].
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+
+    let delay = if let Ok(seconds) = value.parse::<u64>() {
+        Duration::from_secs(seconds)
+    } else {
+        httpdate::parse_http_date(value)
+            .ok()?
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+fn is_transient_request_error(error: &reqwest::Error) -> bool {
+    error.is_timeout()
+        || error.is_connect()
+        || error.is_request()
+        || error.is_body()
+        || error.is_decode()
+}
+
+/// Check if an api error may go away on its own. The error kind is taken from the
+/// [`CrunchyrollError`] variant, but a request error can be anything from a dropped connection to
+/// an error response of the api. crunchyroll-rs doesn't expose the underlying request error, so
+/// only its message is left to distinguish them.
+fn is_transient_api_error(error: &CrunchyrollError) -> bool {
+    match error {
+        CrunchyrollError::Request(context) => {
+            let message = context.message.to_lowercase();
+            [
+                "error sending request",
+                "error decoding response body",
+                "error reading a body",
+                "timed out",
+                "connection reset",
+                "connection refused",
+                "connection aborted",
+                "connection closed",
+                "server error",
+                "too many requests",
+            ]
+            .iter()
+            .any(|m| message.contains(m))
+        }
+        // server errors usually come with a html error page which cannot be decoded as json
+        CrunchyrollError::Decode(context) => context
+            .value
+            .as_ref()
+            .is_some_and(|v| v.to_lowercase().contains("<html")),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crunchyroll_rs::error::CrunchyrollErrorContext;
+
+    fn context(message: &str, value: Option<&str>) -> CrunchyrollErrorContext {
+        CrunchyrollErrorContext {
+            message: message.to_string(),
+            url: None,
+            value: value.map(|v| v.to_string()),
+        }
+    }
+
+    #[test]
+    fn backoff_bounds() {
+        let policy = RetryPolicy::new(5, Duration::from_secs(60));
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(
+                first >= BASE_DELAY / 2 && first <= BASE_DELAY,
+                "{:?}",
+                first
+            );
+
+            let third = policy.backoff(3);
+            assert!(
+                third >= BASE_DELAY * 2 && third <= BASE_DELAY * 4,
+                "{:?}",
+                third
+            );
+
+            for retry in [7, 10, 32, 100, u32::MAX] {
+                let delay = policy.backoff(retry);
+                assert!(
+                    delay >= MAX_DELAY / 2 && delay <= MAX_DELAY,
+                    "{}: {:?}",
+                    retry,
+                    delay
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(parse_retry_after("0"), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("300"), Some(MAX_RETRY_AFTER));
+        assert_eq!(parse_retry_after("86400"), Some(MAX_RETRY_AFTER));
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let in_two_minutes = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(120));
+        let delay = parse_retry_after(&in_two_minutes).unwrap();
+        // the http date has a resolution of one second
+        assert!(
+            delay > Duration::from_secs(117) && delay <= Duration::from_secs(120),
+            "{:?}",
+            delay
+        );
+
+        let in_one_day = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(86400));
+        assert_eq!(parse_retry_after(&in_one_day), Some(MAX_RETRY_AFTER));
+
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retry_after_invalid() {
+        assert_eq!(parse_retry_after(""), None);
+        assert_eq!(parse_retry_after("-5"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn transient_api_errors() {
+        for message in [
+            "error sending request for url (https://www.crunchyroll.com/cms/v2/seasons)",
+            "error decoding response body: expected value at line 1 column 1",
+            "error reading a body from connection: connection reset",
+            "operation timed out",
+            "connection refused (os error 111)",
+            "HTTP status server error (502 Bad Gateway) for url (https://www.crunchyroll.com)",
+            "HTTP status client error (429 Too Many Requests) for url (https://www.crunchyroll.com)",
+        ] {
+            assert!(
+                is_transient_api_error(&CrunchyrollError::Request(context(message, None))),
+                "{}",
+                message
+            )
+        }
+        assert!(is_transient_api_error(&CrunchyrollError::Decode(context(
+            "expected value at 1:1",
+            Some("<html><body>502 Bad Gateway</body></html>")
+        ))))
+    }
+
+    #[test]
+    fn non_transient_api_errors() {
+        for error in [
+            CrunchyrollError::Request(context("cms.not_found - Not found", None)),
+            CrunchyrollError::Request(context("(bad_request) - season_id: invalid", None)),
+            CrunchyrollError::Request(context(
+                "invalid_connection - This device has no connection to the account",
+                None,
+            )),
+            CrunchyrollError::Decode(context(
+                "missing field `id` at 1:20",
+                Some("{\"title\": \"Example\"}"),
+            )),
+            CrunchyrollError::Decode(context("expected value at 1:1", None)),
+            CrunchyrollError::Authentication(context("invalid credentials", None)),
+            CrunchyrollError::Input(context("connection timed out", None)),
+            CrunchyrollError::Internal(context("error sending request", None)),
+        ] {
+            assert!(!is_transient_api_error(&error), "{}", error)
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::utils::context::Context;
 use crate::utils::os::tempfile;
 use anyhow::Result;
 use chrono::NaiveTime;
@@ -17,18 +18,37 @@ pub struct Subtitle {
 }
 
 pub async fn download_subtitle(
+    ctx: &Context,
     subtitle: StreamSubtitle,
     max_length: NaiveTime,
 ) -> Result<TempPath> {
+    let raw = fetch_subtitle(ctx, &subtitle).await?;
+    write_subtitle(raw, max_length)
+}
+
+/// Download the raw subtitle. Unlike [`download_subtitle`] this does not need the video length, so
+/// it can run while the video is still downloading. Use [`write_subtitle`] to store it afterwards.
+pub async fn fetch_subtitle(ctx: &Context, subtitle: &StreamSubtitle) -> Result<Vec<u8>> {
+    let client = ctx.crunchy.client();
+    ctx.retry
+        .fetch(
+            format!("{} subtitle", subtitle.locale),
+            || client.get(&subtitle.url),
+            ctx.rate_limiter.as_deref(),
+            None,
+        )
+        .await
+}
+
+/// Fix a subtitle which was downloaded with [`fetch_subtitle`] and write it to a temporary file.
+pub fn write_subtitle(mut raw: Vec<u8>, max_length: NaiveTime) -> Result<TempPath> {
     let tempfile = tempfile(".ass")?;
     let (mut file, path) = tempfile.into_parts();
 
-    let mut buf = vec![];
-    subtitle.write_to(&mut buf).await?;
-    buf = fix_subtitle_look_and_feel(buf);
-    buf = fix_subtitle_length(buf, max_length);
+    raw = fix_subtitle_look_and_feel(raw);
+    raw = fix_subtitle_length(raw, max_length);
 
-    file.write_all(buf.as_slice())?;
+    file.write_all(raw.as_slice())?;
 
     Ok(path)
 }